@@ -0,0 +1,29 @@
+use std::path::Path;
+
+// libssh2 as built by libssh2-sys lacks libssh2_version(), so the version is read from the
+// header it was built with
+fn main() {
+    println!("cargo:rerun-if-env-changed=DEP_SSH2_INCLUDE");
+    println!("cargo:rustc-check-cfg=cfg(libssh2_agent_identity_path)");
+    let header = std::env::var("DEP_SSH2_INCLUDE")
+        .ok()
+        .and_then(|include| std::fs::read_to_string(Path::new(&include).join("libssh2.h")).ok())
+        .unwrap_or_default();
+    let define = |name: &str| {
+        header.lines().find_map(|l| {
+            l.strip_prefix("#define ")
+                .and_then(|l| l.strip_prefix(name))
+                .filter(|v| v.starts_with(char::is_whitespace))
+                .map(|v| v.trim().trim_matches('"').to_string())
+        })
+    };
+    let version = define("LIBSSH2_VERSION").unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=LIBSSH2_VERSION={}", version);
+    // agents on other sockets need libssh2_agent_set_identity_path from libssh2 1.9, which
+    // an older system libssh2 (LIBSSH2_SYS_USE_PKG_CONFIG) doesn't export
+    let version_num = define("LIBSSH2_VERSION_NUM")
+        .and_then(|v| u32::from_str_radix(v.trim_start_matches("0x"), 16).ok());
+    if matches!(version_num, Some(v) if v >= 0x010900) {
+        println!("cargo:rustc-cfg=libssh2_agent_identity_path");
+    }
+}
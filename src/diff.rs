@@ -0,0 +1,218 @@
+/// Lines of context around each change, as in `diff -u`.
+const CONTEXT: usize = 3;
+/// Largest LCS table built per diff, 8 MB. Diffs run for every host in parallel, so bigger
+/// changes are only reported as differing.
+const MAX_TABLE_CELLS: usize = 1 << 20;
+
+enum Op<'a> {
+    Equal(&'a str),
+    Delete(&'a str),
+    Insert(&'a str),
+}
+
+/// Line based unified diff from `old` to `new`, `None` if they have the same lines.
+pub(crate) fn unified_diff(old: &str, new: &str, old_name: &str, new_name: &str) -> Option<String> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    if old == new {
+        return None;
+    }
+    let mut out = format!("--- {}\n+++ {}\n", old_name, new_name);
+    let ops = match edit_script(&old, &new) {
+        Some(a) => a,
+        None => {
+            out.push_str(&format!(
+                "Differs, too many changed lines to diff ({} and {} lines)\n",
+                old.len(),
+                new.len()
+            ));
+            return Some(out);
+        }
+    };
+    // indices of the ops that change something, hunks are built around them
+    let changes: Vec<usize> = ops
+        .iter()
+        .enumerate()
+        .filter(|(_, op)| !matches!(op, Op::Equal(_)))
+        .map(|(i, _)| i)
+        .collect();
+    let mut i = 0;
+    while i < changes.len() {
+        let start = changes[i].saturating_sub(CONTEXT);
+        let mut end = changes[i];
+        while i < changes.len() && changes[i] <= end + 2 * CONTEXT + 1 {
+            end = changes[i];
+            i += 1;
+        }
+        let end = (end + CONTEXT + 1).min(ops.len());
+        // line numbers of the hunk start, counted from the ops before it
+        let (mut old_line, mut new_line) = (1, 1);
+        for op in &ops[..start] {
+            match op {
+                Op::Equal(_) => {
+                    old_line += 1;
+                    new_line += 1;
+                }
+                Op::Delete(_) => old_line += 1,
+                Op::Insert(_) => new_line += 1,
+            }
+        }
+        let hunk = &ops[start..end];
+        let old_len = hunk
+            .iter()
+            .filter(|op| !matches!(op, Op::Insert(_)))
+            .count();
+        let new_len = hunk
+            .iter()
+            .filter(|op| !matches!(op, Op::Delete(_)))
+            .count();
+        out.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            if old_len == 0 { old_line - 1 } else { old_line },
+            old_len,
+            if new_len == 0 { new_line - 1 } else { new_line },
+            new_len
+        ));
+        for op in hunk {
+            let (prefix, line) = match op {
+                Op::Equal(l) => (' ', l),
+                Op::Delete(l) => ('-', l),
+                Op::Insert(l) => ('+', l),
+            };
+            out.push(prefix);
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    Some(out)
+}
+
+/// Shortest edit script through the longest common subsequence of lines, `None` if the
+/// changed part is too large for the LCS table.
+fn edit_script<'a>(old: &[&'a str], new: &[&'a str]) -> Option<Vec<Op<'a>>> {
+    // the common prefix and suffix stay as they are, only the lines in between are compared
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let changed_old = &old[prefix..old.len() - suffix];
+    let changed_new = &new[prefix..new.len() - suffix];
+    if (changed_old.len() + 1).saturating_mul(changed_new.len() + 1) > MAX_TABLE_CELLS {
+        return None;
+    }
+    let mut ops: Vec<Op> = old[..prefix].iter().map(|l| Op::Equal(l)).collect();
+    ops.extend(lcs_script(changed_old, changed_new));
+    ops.extend(old[old.len() - suffix..].iter().map(|l| Op::Equal(l)));
+    Some(ops)
+}
+
+fn lcs_script<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<Op<'a>> {
+    // lcs[i][j] is the LCS length of old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut ops = Vec::with_capacity(old.len().max(new.len()));
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            ops.push(Op::Equal(old[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            ops.push(Op::Delete(old[i]));
+            i += 1;
+        } else {
+            ops.push(Op::Insert(new[j]));
+            j += 1;
+        }
+    }
+    ops.extend(old[i..].iter().map(|l| Op::Delete(l)));
+    ops.extend(new[j..].iter().map(|l| Op::Insert(l)));
+    ops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(ops: &[Op]) -> String {
+        ops.iter()
+            .map(|op| match op {
+                Op::Equal(l) => format!(" {}", l),
+                Op::Delete(l) => format!("-{}", l),
+                Op::Insert(l) => format!("+{}", l),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn same_lines_have_no_diff() {
+        assert_eq!(unified_diff("a\nb\n", "a\nb", "old", "new"), None);
+    }
+
+    #[test]
+    fn edit_script_keeps_the_common_lines() {
+        let ops = edit_script(&["a", "b", "c", "d"], &["a", "c", "x", "d"]).unwrap();
+        assert_eq!(render(&ops), " a\n-b\n c\n+x\n d");
+    }
+
+    #[test]
+    fn edit_script_handles_empty_sides() {
+        assert_eq!(render(&edit_script(&[], &["a"]).unwrap()), "+a");
+        assert_eq!(render(&edit_script(&["a"], &[]).unwrap()), "-a");
+    }
+
+    #[test]
+    fn hunks_have_context_and_line_numbers() {
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n";
+        let new = "1\n2\n3\n4\n5\nsix\n7\n8\n9\n10\n";
+        let diff = unified_diff(old, new, "baseline", "host").unwrap();
+        assert_eq!(
+            diff,
+            "--- baseline\n+++ host\n@@ -3,7 +3,7 @@\n 3\n 4\n 5\n-6\n+six\n 7\n 8\n 9\n"
+        );
+    }
+
+    #[test]
+    fn distant_changes_get_separate_hunks() {
+        let old: Vec<String> = (0..30).map(|i| i.to_string()).collect();
+        let mut new = old.clone();
+        new[2] = "x".to_string();
+        new[25] = "y".to_string();
+        let diff = unified_diff(&old.join("\n"), &new.join("\n"), "a", "b").unwrap();
+        assert_eq!(diff.matches("@@ -").count(), 2);
+        assert!(diff.contains("@@ -1,6 +1,6 @@\n"));
+        assert!(diff.contains("@@ -23,7 +23,7 @@\n"));
+    }
+
+    #[test]
+    fn large_changes_are_only_reported_as_differing() {
+        let old: String = (0..2000).map(|i| format!("old {}\n", i)).collect();
+        let new: String = (0..2000).map(|i| format!("new {}\n", i)).collect();
+        let diff = unified_diff(&old, &new, "a", "b").unwrap();
+        assert_eq!(
+            diff,
+            "--- a\n+++ b\nDiffers, too many changed lines to diff (2000 and 2000 lines)\n"
+        );
+    }
+
+    #[test]
+    fn large_outputs_with_small_changes_are_diffed() {
+        let old: Vec<String> = (0..100_000).map(|i| i.to_string()).collect();
+        let mut new = old.clone();
+        new[50_000] = "changed".to_string();
+        let diff = unified_diff(&old.join("\n"), &new.join("\n"), "a", "b").unwrap();
+        assert!(diff.contains("-50000\n+changed\n"));
+    }
+}
@@ -1,29 +1,304 @@
-use ansible_rs::{ParallelSshProps, ParallelSshPropsBuilder, Response};
+use ansible_rs::{
+    check_agent, current_user, version_banner, BecomeProps, CommandPolicy, FailureKind,
+    HostKeyStatus, HostOrder, HostOs, NullSink, OutputSink, ParallelSshProps,
+    ParallelSshPropsBuilder, PerHostFileSink, Response, RunSummary, SocksProxy, SshConfig,
+    StdoutSink,
+};
 use chrono::Utc;
 use clap::crate_version;
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use color_backtrace;
 use crossbeam_channel::Receiver;
 use indicatif::{ProgressBar, ProgressStyle};
+use log::{error, warn};
 use rayon::prelude::*;
-use rayon::ThreadPoolBuilder;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::File;
-use std::io::prelude::*;
-use std::net::IpAddr;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
 
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use std::thread::spawn;
-use std::time::{Duration};
+use std::time::{Duration, Instant};
+use xz2::write::XzEncoder;
 
 mod misc;
-use misc::{generate_kv_hosts_from_csv, get_config, hosts_builder, Config};
-use std::net::SocketAddr;
+mod serve;
+use misc::{
+    aggregate_by_result, filter_already_succeeded, generate_kv_hosts_from_csv, get_config,
+    hosts_builder, inventory_hosts, parse_ini_inventory, parse_structured_inventory,
+    read_responses, render, run_inventory_script, CompressionFormat, Config, HostPattern,
+    OutputProps, ResponseWriter, SinkKind,
+};
 
 fn main() {
     color_backtrace::install();
-    let args = App::new("ansible-rs")
+    // warnings like retries and slow hosts are shown unless RUST_LOG says otherwise
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+    let args = cli().get_matches();
+    if args.subcommand_matches("version").is_some() {
+        println!("{}", version_banner());
+        return;
+    }
+    let config_path = args.value_of("config").unwrap();
+    let mut config: Config = confy::load_path(config_path).unwrap_or_else(|e| {
+        eprintln!("Failed loading config {}: {}", config_path, e);
+        std::process::exit(1)
+    });
+    if let Err(e) = apply_overrides(&mut config, &args) {
+        eprintln!("{}", e);
+        std::process::exit(1)
+    }
+    if let Err(errors) = config.validate() {
+        eprintln!("Invalid config {}:", config_path);
+        for e in errors {
+            eprintln!("  {}", e);
+        }
+        std::process::exit(1)
+    }
+    let forks = config.concurrency.unwrap_or(config.threads);
+    if forks > config.threads {
+        warn!(
+            "Only {} of {} forks can run at once, increase threads to use more",
+            config.threads, forks
+        );
+    }
+    if args.is_present("check_agent") {
+        if let Err(e) = check_agent() {
+            eprintln!("Agent check failed: {}", e);
+            std::process::exit(1)
+        }
+    }
+    let mut builder = ParallelSshPropsBuilder::default();
+    builder
+        .threads(config.threads)
+        .agent_connections_pool(config.agent_parallelism)
+        .agent_sockets(config.agent_sockets.clone())
+        .tcp_connections_pool(config.threads as isize)
+        .forks(forks as isize)
+        .read_stream(config.read_stream)
+        .timeout_socket(Duration::from_millis(config.timeout as u64))
+        .timeout_ssh(Duration::from_secs(60));
+    if let Some(username) = &config.username {
+        builder.username(username);
+    }
+    if let Some(secs) = config.slow_host_warning {
+        builder.slow_host_warning(Duration::from_secs(secs));
+    }
+    if !config.allowed_commands.is_empty() || !config.denied_commands.is_empty() {
+        let policy = CommandPolicy::new(&config.allowed_commands, &config.denied_commands)
+            .unwrap_or_else(|e| {
+                eprintln!("Invalid command policy: {}", e);
+                std::process::exit(1)
+            });
+        builder.command_policy(policy);
+    }
+    builder
+        .host_order(config.order)
+        .host_os(config.host_os)
+        .binary_safe(config.binary_safe);
+    if let Some(path) = args.value_of("diff") {
+        builder.baseline(std::fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("Failed reading baseline {}: {}", path, e);
+            std::process::exit(1)
+        }));
+    }
+    if let Some(max) = config.max_output_bytes {
+        builder.max_output_bytes(max);
+    }
+    if let Some(ttl) = config.dns_cache_ttl {
+        builder.dns_cache_ttl(Duration::from_secs(ttl));
+    }
+    if let Some(rate) = config.max_connect_rate {
+        builder.max_connect_rate(rate);
+    }
+    if let Some(batch) = config.serial {
+        builder.serial(batch);
+    }
+    if let Some(shell) = &config.shell {
+        builder.shell(shell);
+    }
+    if let Some(path) = args.value_of("script") {
+        builder.script(std::fs::read(path).unwrap_or_else(|e| {
+            eprintln!("Failed reading script {}: {}", path, e);
+            std::process::exit(1)
+        }));
+        // only names the script in the responses, hosts run it through their shell
+        config.command = format!("{} -s < {}", config.shell.as_deref().unwrap_or("sh"), path);
+    }
+    if config.use_ssh_config {
+        builder.ssh_config(SshConfig::load_default().unwrap_or_else(|e| {
+            eprintln!("Failed loading ssh config: {}", e);
+            std::process::exit(1)
+        }));
+    }
+    if let Some(proxy) = &config.socks_proxy {
+        builder.socks_proxy(proxy.clone());
+    }
+    let password = ask_passwords(&mut config, &args, ask_password).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1)
+    });
+    if let Some(password) = password {
+        let username = config.username.clone().unwrap_or_else(current_user);
+        builder.password_auth(&username, &password);
+    }
+    if let Some(become_props) = config.become_props.take() {
+        builder.become_props(become_props);
+    }
+    if let Some(max) = args.value_of("max_failures") {
+        builder.max_failures(max.parse().unwrap_or_else(|e| {
+            eprintln!("Invalid --max-failures {}: {}", max, e);
+            std::process::exit(1)
+        }));
+    }
+    if let Some(meta) = args.values_of("meta") {
+        let mut metadata = HashMap::new();
+        for m in meta {
+            let mut kv = m.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) if !k.is_empty() => {
+                    metadata.insert(k.to_string(), v.to_string());
+                }
+                _ => {
+                    eprintln!("Invalid --meta {}, expected key=value", m);
+                    std::process::exit(1)
+                }
+            }
+        }
+        builder.run_metadata(metadata);
+    }
+    if let Some(serve_args) = args.subcommand_matches("serve") {
+        let address = serve_args.value_of("listen").unwrap();
+        let token_file = serve_args.value_of("token_file").unwrap();
+        let token = std::fs::read_to_string(token_file).unwrap_or_else(|e| {
+            eprintln!("Failed reading token file {}: {}", token_file, e);
+            std::process::exit(1)
+        });
+        let token = token.trim();
+        if token.is_empty() {
+            eprintln!("Token file {} is empty", token_file);
+            std::process::exit(1)
+        }
+        let allow_remote = serve_args.is_present("allow_remote");
+        if let Err(e) = serve::serve(address, &builder, config.default_port, token, allow_remote) {
+            eprintln!("Server failed: {}", e);
+            std::process::exit(1)
+        }
+        return;
+    }
+
+    if !args.is_present("hosts") {
+        eprintln!("--hosts is required");
+        std::process::exit(1)
+    }
+    let Hosts {
+        mut hosts,
+        usernames: host_usernames,
+        systems: host_systems,
+        group_counts,
+    } = load_hosts(&args, &config);
+    if args.is_present("count") {
+        println!("{} hosts", hosts.len());
+        for (group, count) in &group_counts {
+            println!("  {}: {}", group, count);
+        }
+        return;
+    }
+    if let Some(path) = args.value_of("resume") {
+        let prev = read_responses(Path::new(path)).unwrap_or_else(|e| {
+            eprintln!("Failed reading previous results {}: {}", path, e);
+            std::process::exit(1)
+        });
+        let total = hosts.len();
+        hosts = filter_already_succeeded(hosts, &prev);
+        eprintln!(
+            "Skipping {} hosts that already succeeded",
+            total - hosts.len()
+        );
+    }
+    builder.host_usernames(host_usernames);
+    builder.host_systems(host_systems);
+    let (channel, ssh_processor): (_, ParallelSshProps) = builder
+        .build()
+        .expect("Failed building ssh_processor instance");
+    if let Some(scan_args) = args.subcommand_matches("scan-keys") {
+        let out = scan_args.value_of("out").unwrap();
+        let scans = ssh_processor.scan_keys(hosts.into_iter().map(|(h, _)| h));
+        let mut lines = String::new();
+        for scan in &scans {
+            match (&scan.address, &scan.key_type, &scan.fingerprint) {
+                (Some(address), Some(key_type), Some(fingerprint)) => {
+                    lines += &format!(
+                        "{} {} {} {} {:?}\n",
+                        scan.hostname, address, key_type, fingerprint, scan.status
+                    );
+                    match scan.status {
+                        HostKeyStatus::New => eprintln!("New host key: {}", scan.hostname),
+                        HostKeyStatus::Changed => {
+                            eprintln!("Changed host key: {} {}", scan.hostname, fingerprint)
+                        }
+                        _ => {}
+                    }
+                }
+                _ => eprintln!(
+                    "Failed scanning {}: {}",
+                    scan.hostname,
+                    scan.error.as_deref().unwrap_or_default()
+                ),
+            }
+        }
+        if let Err(e) = std::fs::write(out, lines) {
+            eprintln!("Failed writing {}: {}", out, e);
+            std::process::exit(1)
+        }
+        let count = |status| scans.iter().filter(|s| s.status == status).count();
+        eprintln!(
+            "{} known, {} new, {} changed, {} failed",
+            count(HostKeyStatus::Known),
+            count(HostKeyStatus::New),
+            count(HostKeyStatus::Changed),
+            count(HostKeyStatus::Failed)
+        );
+        return;
+    }
+    let cancelled = ssh_processor.cancel_handle();
+    ctrlc::set_handler(move || {
+        if cancelled.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        eprintln!("Stopping, waiting for running hosts to finish. Press Ctrl-C again to abort");
+    })
+    .expect("Failed setting Ctrl-C handler");
+    let len = hosts.len();
+    let output = config.output.clone();
+    let aggregate = args.is_present("aggregate");
+    let handler = spawn(move || incremental_save(channel, len, output, aggregate));
+    if args.is_present("dry_run") {
+        ssh_processor.dry_run(hosts);
+    } else {
+        ssh_processor.parallel_ssh_process(hosts);
+    }
+    // closes the result channel so incremental_save can finish
+    drop(ssh_processor);
+    let (responses, summary) = handler.join().unwrap();
+    match serde_json::to_string(&summary) {
+        Ok(a) => eprintln!("{}", a),
+        Err(e) => error!("Failed serializing run summary: {}", e),
+    }
+    if aggregate {
+        for (result, hosts) in aggregate_by_result(responses.into_iter()) {
+            println!("{} hosts: {}", hosts.len(), hosts.join(", "));
+            println!("{}", result.trim_end());
+        }
+    }
+}
+
+fn cli() -> App<'static, 'static> {
+    App::new("ansible-rs")
         .version(crate_version!())
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("config")
                 .short("c")
@@ -36,7 +311,7 @@ fn main() {
         .arg(
             Arg::with_name("hosts")
                 .long("hosts")
-                .help("Path to file with hosts")
+                .help("Path to file with hosts, - to read them from stdin")
                 .required(true)
                 .takes_value(true),
         )
@@ -46,42 +321,299 @@ fn main() {
                 .long("format")
                 .takes_value(true)
                 .help("Hosts format")
-                .long_help("Hosts format: csv for key value and empty(default) for list")
+                .long_help(
+                    "Hosts format: csv for key value, ini for ansible inventory, json or yaml for a \
+                     list of hosts or a mapping of hosts to their variables, script for an executable \
+                     printing a dynamic inventory and empty(default) for list",
+                )
                 .default_value(""),
         )
-        .get_matches();
-    let config: Config = confy::load_path(args.value_of("config").unwrap()).unwrap();
+        .arg(
+            Arg::with_name("limit")
+                .short("l")
+                .long("limit")
+                .takes_value(true)
+                .help("Only run on hosts matching the pattern")
+                .long_help(
+                    "Comma separated globs, or regexes prefixed with ~, matched against host names and inventory groups",
+                ),
+        )
+        .arg(
+            Arg::with_name("forks")
+                .long("forks")
+                .takes_value(true)
+                .help("Number of simultaneous SSH sessions, overrides concurrency from config"),
+        )
+        .arg(
+            Arg::with_name("max_failures")
+                .long("max-failures")
+                .takes_value(true)
+                .help("Stop dispatching new hosts after this many failures")
+                .long_help(
+                    "Stop dispatching new hosts after this many failures, hosts already running still finish",
+                ),
+        )
+        .arg(
+            Arg::with_name("meta")
+                .long("meta")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("key=value annotation saved with every response, can be repeated"),
+        )
+        .arg(
+            Arg::with_name("aggregate")
+                .long("aggregate")
+                .help("Print hosts grouped by identical output after the run"),
+        )
+        .arg(
+            Arg::with_name("dry_run")
+                .long("dry-run")
+                .help("Only check that hosts are resolvable and reachable, don't run the command"),
+        )
+        .arg(
+            Arg::with_name("ask_pass")
+                .long("ask-pass")
+                .help("Prompt for the SSH password once and use it for every host"),
+        )
+        .arg(
+            Arg::with_name("ask_become_pass")
+                .long("ask-become-pass")
+                .help("Prompt for the become password once and use it for every host"),
+        )
+        .arg(
+            Arg::with_name("count")
+                .long("count")
+                .help("Print how many hosts would run after filtering, without connecting"),
+        )
+        .arg(
+            Arg::with_name("command")
+                .short("e")
+                .long("command")
+                .takes_value(true)
+                .help("Command to run, overriding the one from the config"),
+        )
+        .arg(
+            Arg::with_name("script")
+                .long("script")
+                .takes_value(true)
+                .conflicts_with("command")
+                .help("Local script piped to the remote shell on every host instead of a command"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .help("File to save responses to, overriding the config. - writes to stdout"),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .takes_value(true)
+                .help("Results of a previous run, hosts that succeeded there are skipped"),
+        )
+        .arg(
+            Arg::with_name("diff")
+                .long("diff")
+                .takes_value(true)
+                .help("File with the expected output, hosts report whether they match it"),
+        )
+        .arg(
+            Arg::with_name("check_agent")
+                .long("check-agent")
+                .help("Abort before connecting if the ssh-agent is unreachable or has no keys"),
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Run commands requested as JSON lines over TCP, streaming back responses")
+                .arg(
+                    Arg::with_name("listen")
+                        .long("listen")
+                        .takes_value(true)
+                        .default_value("127.0.0.1:7878"),
+                )
+                .arg(
+                    Arg::with_name("token_file")
+                        .long("token-file")
+                        .takes_value(true)
+                        .required(true)
+                        .help("File with the token every request must carry")
+                        .long_help(
+                            "File with the token every request must carry. Anyone with the token \
+                             can run any command on any host with your credentials",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("allow_remote")
+                        .long("allow-remote")
+                        .help("Listen on addresses other than loopback, the protocol is unencrypted"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("version")
+                .about("Print the versions of ansible-rs, libssh2 and OpenSSL for bug reports"),
+        )
+        .subcommand(
+            SubCommand::with_name("scan-keys")
+                .about("Record the host key fingerprints of the hosts without authenticating")
+                .arg(
+                    Arg::with_name("out")
+                        .long("out")
+                        .takes_value(true)
+                        .default_value("host_keys.txt")
+                        .help("File the fingerprints are written to"),
+                ),
+        )
+}
+
+/// Applies the flags overriding the config, they win over the config file.
+fn apply_overrides(config: &mut Config, args: &ArgMatches) -> Result<(), String> {
+    if let Some(command) = args.value_of("command") {
+        config.command = command.to_string();
+    }
+    match args.value_of("output") {
+        Some("-") => config.output.sink = SinkKind::Stdout,
+        Some(filename) => {
+            config.output.sink = SinkKind::File;
+            config.output.save_to_file = true;
+            config.output.filename = Some(filename.to_string());
+        }
+        None => {}
+    }
+    if let Some(forks) = args.value_of("forks") {
+        let forks = forks
+            .parse()
+            .map_err(|e| format!("Invalid --forks {}: {}", forks, e))?;
+        config.concurrency = Some(forks);
+    }
+    Ok(())
+}
+
+/// Asks `prompt` for the passwords the flags ask for. The become password goes into the
+/// config's become section, the SSH password is returned for password auth.
+fn ask_passwords(
+    config: &mut Config,
+    args: &ArgMatches,
+    mut prompt: impl FnMut(&str) -> String,
+) -> Result<Option<String>, String> {
+    let password = if args.is_present("ask_pass") {
+        Some(prompt("SSH password: "))
+    } else {
+        None
+    };
+    if args.is_present("ask_become_pass") {
+        let become_props = config
+            .become_props
+            .as_mut()
+            .ok_or("--ask-become-pass needs a become section in the config")?;
+        become_props.password = Some(prompt("Become password: "));
+    }
+    Ok(password)
+}
+
+/// Hosts to run with their commands, along with the usernames and systems the inventory
+/// sets for them and the size of its groups.
+struct Hosts {
+    hosts: Vec<(String, String)>,
+    usernames: HashMap<String, String>,
+    systems: HashMap<String, HostOs>,
+    group_counts: BTreeMap<String, usize>,
+}
+
+/// Reads the hosts in the format given by the flags, without duplicates and filtered by
+/// `--limit`.
+fn load_hosts(args: &ArgMatches, config: &Config) -> Hosts {
     let command = &config.command;
+    let limit = args.value_of("limit").map(|l| {
+        HostPattern::parse_list(l).unwrap_or_else(|e| {
+            eprintln!("Failed parsing --limit: {}", e);
+            std::process::exit(1)
+        })
+    });
 
-    let hosts = if args.value_of("hosts_format").unwrap() == "csv" {
-        let hosts = generate_kv_hosts_from_csv(&args.value_of("hosts").unwrap()).unwrap();
+    let mut usernames = HashMap::new();
+    let mut systems = HashMap::new();
+    let mut group_counts = BTreeMap::new();
+    let hosts_format = args.value_of("hosts_format").unwrap();
+    let hosts_file = args.value_of("hosts").unwrap();
+    let hosts_path = Path::new(hosts_file);
+    let inventory = match hosts_format {
+        "ini" => Some(parse_ini_inventory(hosts_path)),
+        "script" => Some(run_inventory_script(hosts_path)),
+        "json" | "yaml" => Some(parse_structured_inventory(
+            hosts_path,
+            hosts_format == "yaml",
+        )),
+        _ => None,
+    };
+    let is_inventory = inventory.is_some();
+    let mut hosts = if hosts_format == "csv" {
+        generate_kv_hosts_from_csv(hosts_file, config.default_port).unwrap()
+    } else if let Some(inventory) = inventory {
+        let (hosts, users, host_systems) = inventory
+            .and_then(|mut inventory| {
+                // filtered here so patterns can also match group names
+                if let Some(limit) = &limit {
+                    inventory.limit(limit);
+                }
+                group_counts = inventory.group_counts();
+                inventory_hosts(&inventory, config.default_port)
+            })
+            .unwrap_or_else(|e| {
+                eprintln!("Failed parsing inventory: {}", e);
+                std::process::exit(1)
+            });
+        usernames.extend(users);
+        systems.extend(host_systems);
         hosts
             .into_iter()
-            .map(|(ad, com)| (SocketAddr::new(IpAddr::from(ad), 22), com))
+            .map(|(h, vars)| {
+                let command =
+                    render(command, &vars, config.keep_undefined_vars).unwrap_or_else(|e| {
+                        eprintln!("Failed rendering command for {}: {}", h, e);
+                        std::process::exit(1)
+                    });
+                (h, command)
+            })
             .collect()
     } else {
-        let mut map = BTreeMap::new();
-        for h in hosts_builder(Path::new(&args.value_of("hosts").unwrap())) {
-            map.insert(SocketAddr::new(IpAddr::from(h), 22), command.clone());
-        }
-        map
+        hosts_builder(hosts_path, config)
+            .unwrap_or_else(|e| {
+                eprintln!("Failed parsing hosts: {}", e);
+                std::process::exit(1)
+            })
+            .into_iter()
+            .map(|h| (h, command.clone()))
+            .collect::<Vec<_>>()
     };
-    dbg!(&config);
-    ThreadPoolBuilder::new()
-        .num_threads(config.threads)
-        .build_global()
-        .expect("failed creating pool");
-    let (channel, ssh_processor): (_, ParallelSshProps) = ParallelSshPropsBuilder::default()
-        .agent_connections_pool(config.agent_parallelism)
-        .tcp_connections_pool(config.threads as isize)
-        .timeout_socket(Duration::from_millis(config.timeout as u64))
-        .timeout_ssh(Duration::from_secs(60))
-        .build()
-        .expect("Failed building ssh_processor instance");
-    let len = hosts.len();
-    let handler = spawn(move || incremental_save(channel, len));
-    ssh_processor.parallel_ssh_process(hosts);
-    handler.join().unwrap();
+    // hosts listed twice run once, in the position they were first listed at
+    let mut seen = HashSet::new();
+    hosts.retain(|(h, _)| seen.insert(h.clone()));
+    if let Some(limit) = &limit {
+        // inventories are already limited, including hosts matched through their groups
+        if !is_inventory {
+            hosts.retain(|(h, _)| limit.iter().any(|p| p.matches(h)));
+        }
+        if hosts.is_empty() {
+            eprintln!("No hosts match --limit {}", args.value_of("limit").unwrap());
+            std::process::exit(1)
+        }
+    }
+    Hosts {
+        hosts,
+        usernames,
+        systems,
+        group_counts,
+    }
+}
+
+/// Reads a password from the terminal without echoing it.
+fn ask_password(prompt: &str) -> String {
+    rpassword::read_password_from_tty(Some(prompt)).unwrap_or_else(|e| {
+        eprintln!("Failed reading password: {}", e);
+        std::process::exit(1)
+    })
 }
 
 fn progress_bar_creator(queue_len: u64) -> ProgressBar {
@@ -94,16 +626,25 @@ fn progress_bar_creator(queue_len: u64) -> ProgressBar {
     total_hosts_processed
 }
 
-fn config_incremental_folders() -> File {
+fn incremental_dir(output: &OutputProps) -> PathBuf {
+    output.incremental_dir().unwrap_or_else(|e| {
+        eprintln!("Failed creating incremental dir: {}", e);
+        std::process::exit(1)
+    })
+}
+
+fn config_incremental_folders(output: &OutputProps, extension: &str) -> File {
     let datetime = Utc::now().format("%H_%M_%S").to_string();
-    let filename = &datetime;
-    let store_dir_date = Utc::today().format("%d_%B_%Y").to_string();
-    if !Path::new(&store_dir_date).exists() {
-        std::fs::create_dir(Path::new(&store_dir_date))
-            .expect("Failed creating dir for temporary save");
-    }
-    let incremental_name = PathBuf::from(store_dir_date + "/incremental_" + &filename + ".json");
-    File::create(incremental_name).expect("incremental salving failed.")
+    let incremental_name =
+        incremental_dir(output).join(format!("incremental_{}.{}", datetime, extension));
+    File::create(&incremental_name).unwrap_or_else(|e| {
+        eprintln!(
+            "Failed creating incremental file {}: {}",
+            incremental_name.display(),
+            e
+        );
+        std::process::exit(1)
+    })
 }
 enum Stat {
     Ok,
@@ -115,14 +656,7 @@ fn progress_bar_display(queue_len: u64, rx: std::sync::mpsc::Receiver<Stat>) {
     let mut ko = 0;
     let mut token = 0;
     let total = progress_bar_creator(queue_len);
-    for _ in 0..queue_len {
-        let stat = match rx.recv() {
-            Ok(a) => a,
-            Err(e) => {
-                eprintln!("Error receiving stats: {}", e);
-                return;
-            }
-        };
+    for stat in rx {
         match stat {
             Stat::Ok => ok += 1,
             Stat::Fail => ko += 1,
@@ -131,30 +665,408 @@ fn progress_bar_display(queue_len: u64, rx: std::sync::mpsc::Receiver<Stat>) {
         total.inc(1);
         total.set_message(&format!("OK: {}, Failed: {}, Token: {}", ok, ko, token));
     }
+    total.finish();
 }
 
-fn incremental_save(rx: Receiver<Response>, stream_len: usize) {
-    let mut file = config_incremental_folders();
-    let len = stream_len;
-    let (sender, reciever) = std::sync::mpsc::channel();
-    std::thread::spawn(move || progress_bar_display(len as u64, reciever));
-    for _ in 0..len {
-        if let Ok(received) = rx.recv() {
-            let stat = if received.status {
-                Stat::Ok
-            } else if received.result.contains("[-19]") {
-                Stat::TokenFail
-            } else {
-                Stat::Fail
+/// Finishes the xz stream after the responses, which dropping it would do silently.
+struct XzSink(ResponseWriter<XzEncoder<File>>);
+
+impl OutputSink for XzSink {
+    fn write(&mut self, response: &Response) -> Result<(), anyhow::Error> {
+        self.0.write(response)
+    }
+
+    fn finalize(self: Box<Self>) -> Result<(), anyhow::Error> {
+        self.0.finish()?.finish()?;
+        Ok(())
+    }
+}
+
+/// Same as `XzSink` for gzip.
+struct GzSink(ResponseWriter<GzEncoder<File>>);
+
+impl OutputSink for GzSink {
+    fn write(&mut self, response: &Response) -> Result<(), anyhow::Error> {
+        self.0.write(response)
+    }
+
+    fn finalize(self: Box<Self>) -> Result<(), anyhow::Error> {
+        self.0.finish()?.finish()?;
+        Ok(())
+    }
+}
+
+fn output_sink(output: &OutputProps) -> Box<dyn OutputSink> {
+    let format = output.output_format;
+    match output.sink {
+        SinkKind::Null => Box::new(NullSink),
+        SinkKind::Stdout => Box::new(StdoutSink),
+        SinkKind::PerHostFiles => {
+            let dir = match &output.directory {
+                Some(dir) => PathBuf::from(dir),
+                None => {
+                    incremental_dir(output).join(format!("hosts_{}", Utc::now().format("%H_%M_%S")))
+                }
             };
-            if let Err(e) = sender.send(stat) {
-                eprintln!("Error sending stats: {}", e)
+            Box::new(PerHostFileSink::create(&dir).unwrap_or_else(|e| {
+                eprintln!("Failed creating output directory {}: {}", dir.display(), e);
+                std::process::exit(1)
+            }))
+        }
+        SinkKind::File => match output.compression() {
+            CompressionFormat::Xz => {
+                let extension = format!("{}.xz", format.extension());
+                let file =
+                    XzEncoder::new(output_file(output, &extension), output.compression_level);
+                Box::new(XzSink(
+                    ResponseWriter::new(file, format).pretty(output.pretty_format),
+                ))
             }
-            let mut data = serde_json::to_string_pretty(&received).unwrap();
-            data += "\n";
-            file.write_all(data.as_bytes())
-                .expect("Writing for incremental saving failed");
+            CompressionFormat::Gzip => {
+                let extension = format!("{}.gz", format.extension());
+                let file = GzEncoder::new(
+                    output_file(output, &extension),
+                    Compression::new(output.compression_level),
+                );
+                Box::new(GzSink(
+                    ResponseWriter::new(file, format).pretty(output.pretty_format),
+                ))
+            }
+            CompressionFormat::None => Box::new(
+                ResponseWriter::new(output_file(output, format.extension()), format)
+                    .pretty(output.pretty_format),
+            ),
+        },
+    }
+}
+
+/// The configured output file, or a new incremental file in a dated folder.
+fn output_file(output: &OutputProps, extension: &str) -> File {
+    match &output.filename {
+        Some(filename) if output.save_to_file => File::create(filename).unwrap_or_else(|e| {
+            eprintln!("Failed creating output file {}: {}", filename, e);
+            std::process::exit(1)
+        }),
+        _ => config_incremental_folders(output, extension),
+    }
+}
+
+/// Returns the saved responses if `keep` is set, otherwise they only go to the output sink.
+fn incremental_save(
+    rx: Receiver<Response>,
+    stream_len: usize,
+    output: OutputProps,
+    keep: bool,
+) -> (Vec<Response>, RunSummary) {
+    save_responses(rx, stream_len, output_sink(&output), keep)
+}
+
+fn save_responses(
+    rx: Receiver<Response>,
+    stream_len: usize,
+    mut sink: Box<dyn OutputSink>,
+    keep: bool,
+) -> (Vec<Response>, RunSummary) {
+    let start = Instant::now();
+    let len = stream_len;
+    let (sender, reciever) = std::sync::mpsc::channel();
+    let progress = std::thread::spawn(move || progress_bar_display(len as u64, reciever));
+    // the stream ends when the sender is dropped, even if some hosts never reported
+    let mut count = 0;
+    let mut kept = Vec::new();
+    let mut summary = RunSummary::default();
+    for received in rx.iter() {
+        count += 1;
+        summary.add(&received);
+        let stat = if received.status {
+            Stat::Ok
+        } else if received.failure_kind == Some(FailureKind::AgentToken) {
+            Stat::TokenFail
+        } else {
+            Stat::Fail
+        };
+        if let Err(e) = sender.send(stat) {
+            error!("Error sending stats: {}", e)
+        }
+        sink.write(&received)
+            .expect("Writing for incremental saving failed");
+        if keep {
+            kept.push(received);
         }
     }
-    file.flush().expect("Failed flushing");
+    drop(sender);
+    progress.join().ok();
+    if count != len {
+        warn!("Received {} responses out of {} hosts", count, len);
+    }
+    sink.finalize().expect("Failed flushing");
+    summary.elapsed = start.elapsed();
+    (kept, summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{Arc, Mutex};
+
+    /// Keeps the hostnames written in memory, shared with the test as the sink is consumed.
+    struct VecSink {
+        written: Arc<Mutex<Vec<String>>>,
+        finalized: Arc<AtomicBool>,
+    }
+
+    impl OutputSink for VecSink {
+        fn write(&mut self, response: &Response) -> Result<(), anyhow::Error> {
+            self.written.lock().unwrap().push(response.hostname.clone());
+            Ok(())
+        }
+
+        fn finalize(self: Box<Self>) -> Result<(), anyhow::Error> {
+            self.finalized.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    fn response(hostname: &str, status: bool, failure_kind: Option<&str>) -> Response {
+        serde_json::from_value(serde_json::json!({
+            "result": "",
+            "stderr": "",
+            "hostname": hostname,
+            "process_time": {"secs": 1, "nanos": 0},
+            "status": status,
+            "exit_code": if status { 0 } else { 1 },
+            "attempts": 1,
+            "failure_kind": failure_kind,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn responses_are_saved_to_any_sink() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let finalized = Arc::new(AtomicBool::new(false));
+        let sink = VecSink {
+            written: written.clone(),
+            finalized: finalized.clone(),
+        };
+        let (tx, rx) = crossbeam_channel::unbounded();
+        tx.send(response("web1:22", true, None)).unwrap();
+        tx.send(response("web2:22", false, Some("connect")))
+            .unwrap();
+        tx.send(response("web3:22", false, Some("agent_token")))
+            .unwrap();
+        drop(tx);
+        let (kept, summary) = save_responses(rx, 3, Box::new(sink), true);
+        assert_eq!(*written.lock().unwrap(), ["web1:22", "web2:22", "web3:22"]);
+        assert!(finalized.load(Ordering::SeqCst));
+        assert_eq!(kept.len(), 3);
+        assert_eq!(
+            (summary.ok, summary.failed, summary.token_failed),
+            (1, 1, 1)
+        );
+    }
+
+    #[test]
+    fn unkept_responses_only_go_to_the_sink() {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        tx.send(response("web1:22", true, None)).unwrap();
+        drop(tx);
+        let (kept, summary) = save_responses(rx, 1, Box::new(NullSink), false);
+        assert!(kept.is_empty());
+        assert_eq!(summary.total, 1);
+    }
+
+    /// Config loaded from the defaults with the flags applied.
+    fn overridden(flags: &[&str]) -> Result<Config, String> {
+        let args = ["ansible-rs", "--hosts", "hosts.txt"].iter().chain(flags);
+        let args = cli().get_matches_from_safe(args).map_err(|e| e.message)?;
+        let mut config = Config::default();
+        config.output.filename = Some("from_config.json".to_string());
+        apply_overrides(&mut config, &args)?;
+        Ok(config)
+    }
+
+    #[test]
+    fn output_flag_wins_over_the_config() {
+        let config = overridden(&["--output", "from_cli.json"]).unwrap();
+        assert_eq!(config.output.filename.as_deref(), Some("from_cli.json"));
+        assert_eq!(config.output.sink, SinkKind::File);
+        assert!(config.output.save_to_file);
+        let config = overridden(&["-o", "-"]).unwrap();
+        assert_eq!(config.output.sink, SinkKind::Stdout);
+        let config = overridden(&[]).unwrap();
+        assert_eq!(config.output.filename.as_deref(), Some("from_config.json"));
+    }
+
+    #[test]
+    fn command_flag_wins_over_the_config() {
+        let config = overridden(&["-e", "df -h"]).unwrap();
+        assert_eq!(config.command, "df -h");
+        assert_eq!(overridden(&[]).unwrap().command, "uptime");
+        // a script replaces the command, the two can't be combined
+        let e = overridden(&["--command", "df -h", "--script", "check.sh"]).unwrap_err();
+        assert!(e.contains("cannot be used with"), "{}", e);
+    }
+
+    #[test]
+    fn forks_flag_is_kept_apart_from_threads() {
+        let config = overridden(&["--forks", "50"]).unwrap();
+        assert_eq!(config.concurrency, Some(50));
+        assert_eq!(config.threads, Config::default().threads);
+        assert_eq!(overridden(&[]).unwrap().concurrency, None);
+        let e = overridden(&["--forks", "many"]).unwrap_err();
+        assert!(e.starts_with("Invalid --forks many: "), "{}", e);
+    }
+
+    /// Passwords asked for by the flags, answered with the prompt text in capitals.
+    fn asked(flags: &[&str], config: &mut Config) -> (Result<Option<String>, String>, Vec<String>) {
+        let args = ["ansible-rs", "--hosts", "hosts.txt"].iter().chain(flags);
+        let args = cli().get_matches_from(args);
+        let mut prompts = Vec::new();
+        let password = ask_passwords(config, &args, |prompt| {
+            prompts.push(prompt.to_string());
+            prompt.to_uppercase()
+        });
+        (password, prompts)
+    }
+
+    #[test]
+    fn password_flags_prompt_once_each() {
+        let mut config = Config {
+            become_props: Some(toml::from_str("user = \"root\"").unwrap()),
+            ..Config::default()
+        };
+        let (password, prompts) = asked(&["--ask-pass", "--ask-become-pass"], &mut config);
+        assert_eq!(password.unwrap().as_deref(), Some("SSH PASSWORD: "));
+        assert_eq!(prompts, ["SSH password: ", "Become password: "]);
+        let become_props = config.become_props.as_ref().unwrap();
+        assert_eq!(become_props.password.as_deref(), Some("BECOME PASSWORD: "));
+
+        let (password, prompts) = asked(&[], &mut Config::default());
+        assert_eq!(password, Ok(None));
+        assert!(prompts.is_empty());
+    }
+
+    #[test]
+    fn become_passwords_need_a_become_section() {
+        let (password, _) = asked(&["--ask-become-pass"], &mut Config::default());
+        assert_eq!(
+            password,
+            Err("--ask-become-pass needs a become section in the config".to_string())
+        );
+    }
+
+    fn saved(output: OutputProps, responses: Vec<Response>) {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let len = responses.len();
+        for response in responses {
+            tx.send(response).unwrap();
+        }
+        drop(tx);
+        incremental_save(rx, len, output, false);
+    }
+
+    #[test]
+    fn compressed_incremental_files_are_read_back() {
+        let dir = std::env::temp_dir().join(format!("ansible-rs-xz-{}", std::process::id()));
+        let output = OutputProps {
+            compress: true,
+            incremental_dir: Some(dir.clone()),
+            ..OutputProps::default()
+        };
+        saved(
+            output.clone(),
+            vec![
+                response("web1:22", true, None),
+                response("web2:22", false, Some("connect")),
+            ],
+        );
+        let files: Vec<PathBuf> = std::fs::read_dir(output.incremental_dir().unwrap())
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 1);
+        let name = files[0].file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("incremental_") && name.ends_with(".json.xz"));
+        let read = read_responses(&files[0]).unwrap();
+        let hosts: Vec<_> = read.iter().map(|r| r.hostname.as_str()).collect();
+        assert_eq!(hosts, ["web1:22", "web2:22"]);
+        assert_eq!(read[1].failure_kind, Some(FailureKind::Connect));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compression_level_reaches_the_encoder() {
+        let dir = std::env::temp_dir().join(format!("ansible-rs-level-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let size = |level| {
+            let filename = dir.join(format!("level_{}.json.xz", level));
+            let output = OutputProps {
+                save_to_file: true,
+                filename: Some(filename.to_str().unwrap().to_string()),
+                compress: true,
+                compression_level: level,
+                ..OutputProps::default()
+            };
+            let responses = (0..500)
+                .map(|i| response(&format!("web{}.example.com:22", i), i % 7 != 0, None))
+                .collect();
+            saved(output, responses);
+            assert_eq!(read_responses(&filename).unwrap().len(), 500);
+            std::fs::metadata(&filename).unwrap().len()
+        };
+        assert!(size(0) > size(9));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn short_result_streams_still_finish() {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let producer = std::thread::spawn(move || {
+            tx.send(response("web1:22", true, None)).unwrap();
+            // the other two hosts never report
+        });
+        let (done, finished) = std::sync::mpsc::channel();
+        std::thread::spawn(move || done.send(save_responses(rx, 3, Box::new(NullSink), true)));
+        let (kept, summary) = finished.recv_timeout(Duration::from_secs(10)).unwrap();
+        producer.join().unwrap();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(summary.total, 1);
+    }
+
+    /// Hosts loaded from `content` in `format`, with the flags applied.
+    fn loaded_hosts(format: &str, content: &str, flags: &[&str]) -> Hosts {
+        let dir = std::env::temp_dir().join(format!("ansible-rs-hosts-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("hosts.{}", format));
+        std::fs::write(&path, content).unwrap();
+        let base = [
+            "ansible-rs",
+            "--hosts",
+            path.to_str().unwrap(),
+            "-f",
+            format,
+        ];
+        let args = cli().get_matches_from(base.iter().chain(flags));
+        let hosts = load_hosts(&args, &Config::default());
+        std::fs::remove_file(&path).unwrap();
+        hosts
+    }
+
+    #[test]
+    fn counts_are_taken_after_the_limit() {
+        let inventory = "[web]\nweb1\nweb2\nweb3\n\n[db]\ndb1\ndb2\n";
+        let all = loaded_hosts("ini", inventory, &[]);
+        assert_eq!(all.hosts.len(), 5);
+        let limited = loaded_hosts("ini", inventory, &["--limit", "web,db1"]);
+        assert_eq!(limited.hosts.len(), 4);
+        let counts: Vec<_> = limited.group_counts.into_iter().collect();
+        assert_eq!(counts, [("db".to_string(), 1), ("web".to_string(), 3)]);
+
+        let list = loaded_hosts("", "web1\nweb2\ndb1\nweb1\n", &["-l", "web*"]);
+        let hosts: Vec<_> = list.hosts.iter().map(|(h, _)| h.as_str()).collect();
+        assert_eq!(hosts, ["web1:22", "web2:22"]);
+        assert!(list.group_counts.is_empty());
+    }
 }
@@ -1,11 +1,62 @@
-use crate::Response;
+use crate::{BecomeProps, HostOrder, HostOs, OutputSink, Response, SocksProxy};
+use ansible_rs::split_host_port;
+use anyhow::Error;
+use chrono::Utc;
+use flate2::read::GzDecoder;
+use glob::Pattern;
+use log::{error, warn};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::net::Ipv4Addr;
-use std::path::Path;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use xz2::read::XzDecoder;
+
+/// Where responses go, `file` uses `output_format` and `compress`.
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SinkKind {
+    #[default]
+    File,
+    Stdout,
+    Null,
+    /// A JSON file per host in `directory`.
+    #[serde(rename = "per_host_files")]
+    PerHostFiles,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Json,
+    Yaml,
+    Jsonl,
+    Csv,
+}
+
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Json => "json",
+            OutputFormat::Yaml => "yaml",
+            OutputFormat::Jsonl => "jsonl",
+            OutputFormat::Csv => "csv",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionFormat {
+    #[default]
+    None,
+    Xz,
+    Gzip,
+}
 
 #[derive(Deserialize, Debug, Clone, Serialize)]
 pub struct OutputProps {
@@ -14,17 +65,164 @@ pub struct OutputProps {
     pub pretty_format: bool,
     pub show_progress: bool,
     pub keep_incremental_data: Option<bool>,
+    #[serde(default)]
+    pub output_format: OutputFormat,
+    /// Same as `compression = "xz"`, kept for older configs.
+    #[serde(default)]
+    pub compress: bool,
+    #[serde(default)]
+    pub compression: CompressionFormat,
+    /// From 0, fastest, to 9, smallest.
+    #[serde(default = "default_compression_level")]
+    pub compression_level: u32,
+    #[serde(default)]
+    pub sink: SinkKind,
+    /// Directory of the `per_host_files` sink, a new one next to the incremental files if unset.
+    #[serde(default)]
+    pub directory: Option<String>,
+    /// Where incremental files go, in a folder per day. A folder in the system temp dir if unset.
+    #[serde(default)]
+    pub incremental_dir: Option<PathBuf>,
 }
 
 #[derive(Deserialize, Debug, Clone, Serialize)]
 pub struct Config {
+    /// Size of the worker pool, also used for the TCP probes.
     pub threads: usize,
+    /// Simultaneous SSH sessions, defaults to `threads`. Can't exceed `threads` in practice.
+    #[serde(default)]
+    pub concurrency: Option<usize>,
     pub agent_parallelism: isize,
+    /// Agent sockets used in turn instead of `$SSH_AUTH_SOCK`.
+    #[serde(default)]
+    pub agent_sockets: Vec<PathBuf>,
+    /// Command to run. With an INI inventory `{{ var }}` is replaced by the host's variable.
     pub command: String,
+    /// Leave `{{ var }}` of undefined variables in the command instead of failing.
+    #[serde(default)]
+    pub keep_undefined_vars: bool,
     pub timeout: u32,
+    pub username: Option<String>,
+    #[serde(default = "default_port")]
+    pub default_port: u16,
+    #[serde(default)]
+    pub allow_large_ranges: bool,
+    /// Channel stream read as the result, 0 is stdout.
+    #[serde(default)]
+    pub read_stream: i32,
+    #[serde(default)]
+    pub socks_proxy: Option<SocksProxy>,
+    #[serde(default)]
+    pub order: HostOrder,
+    /// Base64 encode binary output instead of replacing invalid UTF-8.
+    #[serde(default)]
+    pub binary_safe: bool,
+    /// Bytes of output kept per host, longer output is truncated. Unlimited if unset.
+    #[serde(default)]
+    pub max_output_bytes: Option<usize>,
+    /// OS of hosts without a `host_os` inventory variable.
+    #[serde(default)]
+    pub host_os: HostOs,
+    /// Run hosts in batches of this size instead of all at once.
+    #[serde(default)]
+    pub serial: Option<usize>,
+    /// Glob patterns of commands allowed to run, any command if empty.
+    #[serde(default)]
+    pub allowed_commands: Vec<String>,
+    /// Glob patterns of commands never run, checked before `allowed_commands`.
+    #[serde(default)]
+    pub denied_commands: Vec<String>,
+    /// Shell commands are run with, e.g. `/bin/sh`, instead of the remote login shell.
+    #[serde(default)]
+    pub shell: Option<String>,
+    /// Resolve hosts through `~/.ssh/config`.
+    #[serde(default)]
+    pub use_ssh_config: bool,
+    /// Seconds resolved host names are cached for, every host is looked up if unset.
+    #[serde(default)]
+    pub dns_cache_ttl: Option<u64>,
+    /// Maximum new connections per second, unlimited if unset.
+    #[serde(default)]
+    pub max_connect_rate: Option<u32>,
+    /// Seconds after which hosts that are still running get logged.
+    #[serde(default)]
+    pub slow_host_warning: Option<u64>,
+    #[serde(default, rename = "become")]
+    pub become_props: Option<BecomeProps>,
     pub output: OutputProps,
 }
 
+impl Config {
+    /// Checks every field, returning all problems instead of stopping at the first one.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        if self.threads == 0 {
+            errors.push("threads must be greater than 0".to_string());
+        }
+        if self.concurrency == Some(0) {
+            errors.push("concurrency must be greater than 0".to_string());
+        }
+        if self.agent_parallelism < 1 {
+            errors.push("agent_parallelism must be at least 1".to_string());
+        }
+        if self.slow_host_warning == Some(0) {
+            errors.push("slow_host_warning must be greater than 0".to_string());
+        }
+        if self.dns_cache_ttl == Some(0) {
+            errors.push("dns_cache_ttl must be greater than 0".to_string());
+        }
+        if self.max_connect_rate == Some(0) {
+            errors.push("max_connect_rate must be greater than 0".to_string());
+        }
+        if self.serial == Some(0) {
+            errors.push("serial must be at least 1".to_string());
+        }
+        if let Some(dir) = &self.output.incremental_dir {
+            if dir.exists() && !dir.is_dir() {
+                errors.push(format!(
+                    "output.incremental_dir {} is not a directory",
+                    dir.display()
+                ));
+            }
+        }
+        if self.timeout == 0 {
+            errors.push("timeout must be greater than 0".to_string());
+        }
+        if self.output.save_to_file && self.output.filename.as_deref().unwrap_or("").is_empty() {
+            errors.push("output.filename must be set when output.save_to_file is true".to_string());
+        }
+        if self.output.compression_level > 9 {
+            errors.push("output.compression_level must be between 0 and 9".to_string());
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+impl OutputProps {
+    pub fn compression(&self) -> CompressionFormat {
+        match self.compression {
+            CompressionFormat::None if self.compress => CompressionFormat::Xz,
+            a => a,
+        }
+    }
+
+    /// Today's folder for incremental files, created if missing.
+    pub fn incremental_dir(&self) -> Result<PathBuf, Error> {
+        let base = match &self.incremental_dir {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.clone(),
+            _ => std::env::temp_dir().join("ansible-rs"),
+        };
+        let dir = base.join(Utc::now().format("%d_%B_%Y").to_string());
+        fs::create_dir_all(&dir)
+            .map_err(|e| Error::msg(format!("Failed creating {}: {}", dir.display(), e)))?;
+        Ok(dir)
+    }
+}
+
 impl Default for OutputProps {
     fn default() -> Self {
         OutputProps {
@@ -33,6 +231,13 @@ impl Default for OutputProps {
             pretty_format: false,
             show_progress: false,
             keep_incremental_data: Some(false),
+            output_format: OutputFormat::default(),
+            compress: false,
+            compression: CompressionFormat::default(),
+            compression_level: default_compression_level(),
+            sink: SinkKind::default(),
+            directory: None,
+            incremental_dir: None,
         }
     }
 }
@@ -41,100 +246,1471 @@ impl Default for Config {
     fn default() -> Self {
         Config {
             threads: 10,
+            concurrency: None,
             agent_parallelism: 1,
+            agent_sockets: Vec::new(),
             command: "uptime".to_string(),
+            keep_undefined_vars: false,
             output: OutputProps::default(),
             timeout: 60,
+            username: None,
+            default_port: default_port(),
+            allow_large_ranges: false,
+            read_stream: 0,
+            socks_proxy: None,
+            use_ssh_config: false,
+            shell: None,
+            order: HostOrder::default(),
+            host_os: HostOs::default(),
+            binary_safe: false,
+            max_output_bytes: None,
+            serial: None,
+            allowed_commands: Vec::new(),
+            denied_commands: Vec::new(),
+            dns_cache_ttl: None,
+            max_connect_rate: None,
+            slow_host_warning: None,
+            become_props: None,
         }
     }
 }
 
-pub fn hosts_builder(path: &Path) -> Vec<Ipv4Addr> {
-    let file = File::open(path).expect("Unable to open the file");
-    let reader = BufReader::new(file);
-    reader
+fn default_compression_level() -> u32 {
+    6
+}
+
+fn default_port() -> u16 {
+    22
+}
+
+fn is_valid_hostname(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Normalizes a host entry to `host:port`. Hostnames are kept as is and resolved later.
+pub fn parse_host(line: &str, default_port: u16) -> Option<String> {
+    if let Ok(a) = line.parse::<SocketAddr>() {
+        return Some(a.to_string());
+    }
+    // bare IPv6 addresses contain colons too, so they are tried before host:port
+    let bare = line
+        .strip_prefix('[')
+        .and_then(|l| l.strip_suffix(']'))
+        .unwrap_or(line);
+    if let Ok(ip) = bare.parse::<IpAddr>() {
+        return Some(SocketAddr::new(ip, default_port).to_string());
+    }
+    let (name, port) = match line.rsplit_once(':') {
+        Some((name, port)) => (name, port.parse().ok()?),
+        None => (line, default_port),
+    };
+    if is_valid_hostname(name) {
+        Some(format!("{}:{}", name, port))
+    } else {
+        None
+    }
+}
+
+/// Largest block expanded without `allow_large_ranges`, a /16.
+const MAX_CIDR_HOSTS: u64 = 1 << 16;
+
+pub fn expand_cidr(block: &str, allow_large_ranges: bool) -> Result<Vec<Ipv4Addr>, Error> {
+    let (ip, prefix) = block
+        .split_once('/')
+        .ok_or_else(|| Error::msg(format!("{} is not a CIDR block", block)))?;
+    let ip: Ipv4Addr = ip
+        .parse()
+        .map_err(|e| Error::msg(format!("Invalid address in {}: {}", block, e)))?;
+    let prefix: u32 = match prefix.parse() {
+        Ok(a) if a <= 32 => a,
+        _ => return Err(Error::msg(format!("Invalid prefix length in {}", block))),
+    };
+    let size = 1u64 << (32 - prefix);
+    if size > MAX_CIDR_HOSTS && !allow_large_ranges {
+        return Err(Error::msg(format!(
+            "{} expands to {} addresses, set allow_large_ranges to scan it",
+            block, size
+        )));
+    }
+    let mask = if prefix == 0 {
+        0
+    } else {
+        u32::MAX << (32 - prefix)
+    };
+    let network = u32::from(ip) & mask;
+    let (first, last) = if (24..=30).contains(&prefix) {
+        // skip network and broadcast addresses
+        (network as u64 + 1, network as u64 + size - 2)
+    } else {
+        (network as u64, network as u64 + size - 1)
+    };
+    Ok((first..=last).map(|a| Ipv4Addr::from(a as u32)).collect())
+}
+
+/// Reads hosts from `path`, or from stdin when it is `-`.
+pub fn hosts_builder(path: &Path, config: &Config) -> Result<Vec<String>, Error> {
+    if path == Path::new("-") {
+        let stdin = std::io::stdin();
+        let lock = stdin.lock();
+        return parse_hosts(lock, config);
+    }
+    let file = File::open(path)
+        .map_err(|e| Error::msg(format!("Unable to open {}: {}", path.display(), e)))?;
+    parse_hosts(BufReader::new(file), config)
+}
+
+/// Parses newline separated hosts, expanding CIDR blocks.
+pub fn parse_hosts<R: BufRead>(reader: R, config: &Config) -> Result<Vec<String>, Error> {
+    let mut hosts = Vec::new();
+    for l in reader
         .lines()
         .map(|l| l.unwrap_or("Error reading line".to_string()))
         .map(|l| l.replace("\"", ""))
         .map(|l| l.replace("'", ""))
-        .map(|l| l.parse())
-        .filter_map(Result::ok)
-        .collect()
+    {
+        let l = l.trim();
+        if l.is_empty() {
+            continue;
+        }
+        if l.contains('/') {
+            let block = expand_cidr(l, config.allow_large_ranges)?;
+            hosts.extend(
+                block
+                    .into_iter()
+                    .map(|ip| SocketAddr::new(IpAddr::from(ip), config.default_port).to_string()),
+            );
+            continue;
+        }
+        match parse_host(l, config.default_port) {
+            Some(host) => hosts.push(host),
+            None => warn!("Skipping invalid host entry: {}", l),
+        }
+    }
+    Ok(hosts)
 }
 
 pub fn generate_kv_hosts_from_csv(
     path: &str,
-) -> Result<BTreeMap<Ipv4Addr, String>, std::io::Error> {
+    default_port: u16,
+) -> Result<Vec<(String, String)>, std::io::Error> {
     let mut rd = csv::ReaderBuilder::new().from_path(Path::new(path))?;
-    let mut map = BTreeMap::new();
+    let mut hosts = Vec::new();
     for res in rd.records() {
         let rec = match res {
             Ok(a) => a,
             Err(_) => continue,
         };
-        let k: Ipv4Addr = match rec.get(0).unwrap().parse() {
-            Ok(a) => a,
-            Err(_) => continue,
-        };
         let v = rec.get(1).unwrap();
-        println!("{} {}", &k, &v);
-        map.insert(k, v.to_string());
+        let port = match rec.get(2).map(str::trim) {
+            None | Some("") => default_port,
+            Some(p) => match p.parse() {
+                Ok(a) => a,
+                Err(_) => continue,
+            },
+        };
+        let k = match parse_host(rec.get(0).unwrap().trim(), port) {
+            Some(a) => a,
+            None => {
+                warn!("Skipping invalid host entry: {}", rec.get(0).unwrap());
+                continue;
+            }
+        };
+        hosts.push((k, v.to_string()));
+    }
+    Ok(hosts)
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct InventoryGroup {
+    pub hosts: Vec<String>,
+    pub children: Vec<String>,
+    /// Variables of the group's `[name:vars]` section.
+    pub vars: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Inventory {
+    /// Host name to its inline variables, e.g. `ansible_port`.
+    pub hosts: BTreeMap<String, BTreeMap<String, String>>,
+    pub groups: BTreeMap<String, InventoryGroup>,
+}
+
+impl Inventory {
+    pub fn port(&self, host: &str) -> Result<Option<u16>, Error> {
+        match self.hosts.get(host).and_then(|v| v.get("ansible_port")) {
+            None => Ok(None),
+            Some(p) => p
+                .parse()
+                .map(Some)
+                .map_err(|e| Error::msg(format!("Invalid ansible_port for {}: {}", host, e))),
+        }
+    }
+
+    pub fn os(&self, host: &str) -> Result<Option<HostOs>, Error> {
+        match self.hosts.get(host).and_then(|v| v.get("host_os")) {
+            None => Ok(None),
+            Some(os) => match os.to_lowercase().as_str() {
+                "unix" => Ok(Some(HostOs::Unix)),
+                "windows" => Ok(Some(HostOs::Windows)),
+                _ => Err(Error::msg(format!(
+                    "Invalid host_os {} for {}, expected unix or windows",
+                    os, host
+                ))),
+            },
+        }
+    }
+
+    pub fn user(&self, host: &str) -> Option<&str> {
+        self.hosts
+            .get(host)
+            .and_then(|v| v.get("ansible_user"))
+            .map(String::as_str)
+    }
+
+    /// Variables of `host` for templating, later ones winning: `inventory_hostname`, the
+    /// vars of `all`, of groups the host is in through child groups, of groups listing it
+    /// directly, then its inline variables.
+    pub fn vars(&self, host: &str) -> BTreeMap<String, String> {
+        let mut vars = BTreeMap::new();
+        vars.insert("inventory_hostname".to_string(), host.to_string());
+        if let Some(all) = self.groups.get("all") {
+            vars.extend(all.vars.clone());
+        }
+        let (direct, indirect): (Vec<_>, Vec<_>) = self
+            .groups
+            .iter()
+            .filter(|(name, _)| *name != "all" && self.group_hosts(name).contains(host))
+            .partition(|(_, g)| g.hosts.iter().any(|h| h == host));
+        for (_, group) in indirect.into_iter().chain(direct) {
+            vars.extend(group.vars.clone());
+        }
+        if let Some(host_vars) = self.hosts.get(host) {
+            vars.extend(host_vars.clone());
+        }
+        vars
+    }
+
+    /// Hosts of `group` including the ones of its child groups.
+    pub fn group_hosts(&self, group: &str) -> BTreeSet<&str> {
+        let mut hosts = BTreeSet::new();
+        let mut seen = BTreeSet::new();
+        let mut queue = vec![group];
+        while let Some(name) = queue.pop() {
+            if !seen.insert(name) {
+                continue;
+            }
+            if let Some(g) = self.groups.get(name) {
+                hosts.extend(g.hosts.iter().map(String::as_str));
+                queue.extend(g.children.iter().map(String::as_str));
+            }
+        }
+        hosts
+    }
+
+    /// Number of hosts in every group that has any, child groups included.
+    pub fn group_counts(&self) -> BTreeMap<String, usize> {
+        self.groups
+            .keys()
+            .map(|g| {
+                let count = self
+                    .group_hosts(g)
+                    .into_iter()
+                    .filter(|h| self.hosts.contains_key(*h))
+                    .count();
+                (g.clone(), count)
+            })
+            .filter(|(_, count)| *count > 0)
+            .collect()
+    }
+
+    /// Keeps the hosts matching one of `patterns` by name or through one of their groups.
+    pub fn limit(&mut self, patterns: &[HostPattern]) {
+        let mut selected: BTreeSet<String> = self
+            .hosts
+            .keys()
+            .filter(|h| patterns.iter().any(|p| p.matches(h)))
+            .cloned()
+            .collect();
+        for group in self.groups.keys() {
+            if patterns.iter().any(|p| p.matches(group)) {
+                selected.extend(self.group_hosts(group).into_iter().map(String::from));
+            }
+        }
+        let hosts = std::mem::take(&mut self.hosts);
+        self.hosts = hosts
+            .into_iter()
+            .filter(|(h, _)| selected.contains(h))
+            .collect();
+    }
+}
+
+/// One entry of a `--limit` expression: a glob, or a regex when prefixed with `~` like in Ansible.
+pub enum HostPattern {
+    Glob(Pattern),
+    Regex(Regex),
+}
+
+impl HostPattern {
+    /// Parses a comma separated list of patterns, e.g. `web*,~db[0-9]+`.
+    pub fn parse_list(limit: &str) -> Result<Vec<HostPattern>, Error> {
+        limit
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(|p| match p.strip_prefix('~') {
+                Some(re) => Regex::new(re)
+                    .map(HostPattern::Regex)
+                    .map_err(|e| Error::msg(format!("Invalid regex {}: {}", re, e))),
+                None => Pattern::new(p)
+                    .map(HostPattern::Glob)
+                    .map_err(|e| Error::msg(format!("Invalid pattern {}: {}", p, e))),
+            })
+            .collect()
+    }
+
+    /// Matches either the whole `host:port` string or just the host part.
+    pub fn matches(&self, host: &str) -> bool {
+        let name = host_name(host);
+        match self {
+            HostPattern::Glob(p) => p.matches(host) || p.matches(name),
+            HostPattern::Regex(r) => r.is_match(host) || r.is_match(name),
+        }
+    }
+}
+
+/// Strips the port from `host:port`, `[v6]:port` and bracketed addresses.
+fn host_name(host: &str) -> &str {
+    split_host_port(host).0
+}
+
+enum IniSection {
+    Hosts(String),
+    Children(String),
+    Vars(String),
+}
+
+/// Parses an Ansible style INI inventory. Hosts listed before any section go to `ungrouped`.
+pub fn parse_ini_inventory(path: &Path) -> Result<Inventory, Error> {
+    let content = fs::read_to_string(path)?;
+    let mut inventory = Inventory::default();
+    let mut section = IniSection::Hosts("ungrouped".to_string());
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if line.starts_with('[') {
+            let header = line
+                .strip_prefix('[')
+                .and_then(|l| l.strip_suffix(']'))
+                .ok_or_else(|| Error::msg(format!("Malformed section at line {}", number + 1)))?;
+            section = match header.split_once(':') {
+                None => IniSection::Hosts(header.to_string()),
+                Some((name, "children")) => IniSection::Children(name.to_string()),
+                Some((name, "vars")) => IniSection::Vars(name.to_string()),
+                Some((_, kind)) => {
+                    return Err(Error::msg(format!(
+                        "Unknown section type {} at line {}",
+                        kind,
+                        number + 1
+                    )))
+                }
+            };
+            let name = match &section {
+                IniSection::Hosts(name) | IniSection::Children(name) | IniSection::Vars(name) => {
+                    name
+                }
+            };
+            inventory.groups.entry(name.clone()).or_default();
+            continue;
+        }
+        match &section {
+            IniSection::Hosts(group) => {
+                let mut parts = line.split_whitespace();
+                let host = parts.next().unwrap_or_default().to_string();
+                let vars = inventory.hosts.entry(host.clone()).or_default();
+                for var in parts {
+                    let (k, v) = var.split_once('=').ok_or_else(|| {
+                        Error::msg(format!("Malformed variable {} at line {}", var, number + 1))
+                    })?;
+                    vars.insert(
+                        k.to_string(),
+                        v.trim_matches(|c| c == '"' || c == '\'').to_string(),
+                    );
+                }
+                inventory
+                    .groups
+                    .entry(group.clone())
+                    .or_default()
+                    .hosts
+                    .push(host);
+            }
+            IniSection::Children(group) => inventory
+                .groups
+                .entry(group.clone())
+                .or_default()
+                .children
+                .push(line.to_string()),
+            IniSection::Vars(group) => {
+                let (k, v) = line.split_once('=').ok_or_else(|| {
+                    Error::msg(format!(
+                        "Malformed variable {} at line {}",
+                        line,
+                        number + 1
+                    ))
+                })?;
+                inventory
+                    .groups
+                    .entry(group.clone())
+                    .or_default()
+                    .vars
+                    .insert(
+                        k.trim().to_string(),
+                        v.trim().trim_matches(|c| c == '"' || c == '\'').to_string(),
+                    );
+            }
+        }
+    }
+    if inventory
+        .groups
+        .get("ungrouped")
+        .is_some_and(|g| g.hosts.is_empty())
+    {
+        inventory.groups.remove("ungrouped");
+    }
+    Ok(inventory)
+}
+
+/// Host and port of an inventory host and its variables for templating.
+pub type InventoryHost = (String, BTreeMap<String, String>);
+
+/// Inventory hosts with their `ansible_user` and `host_os` overrides.
+pub type InventoryHosts = (
+    Vec<InventoryHost>,
+    Vec<(String, String)>,
+    Vec<(String, HostOs)>,
+);
+
+/// Hosts of a JSON or YAML hosts file, a list of names or a mapping of names to variables.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StructuredHosts {
+    Names(Vec<String>),
+    WithVars(BTreeMap<String, Option<BTreeMap<String, ScalarVar>>>),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ScalarVar {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Text(String),
+}
+
+impl std::fmt::Display for ScalarVar {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScalarVar::Bool(a) => write!(f, "{}", a),
+            ScalarVar::Int(a) => write!(f, "{}", a),
+            ScalarVar::Float(a) => write!(f, "{}", a),
+            ScalarVar::Text(a) => write!(f, "{}", a),
+        }
+    }
+}
+
+/// Reads a JSON, or with `yaml` a YAML, hosts file into an inventory with every host in
+/// `ungrouped`. Variables like `ansible_port` work as in INI inventories.
+pub fn parse_structured_inventory(path: &Path, yaml: bool) -> Result<Inventory, Error> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| Error::msg(format!("Unable to open {}: {}", path.display(), e)))?;
+    let hosts: StructuredHosts = if yaml {
+        serde_yaml::from_str(&content)?
+    } else {
+        serde_json::from_str(&content)?
+    };
+    let hosts: BTreeMap<String, BTreeMap<String, String>> = match hosts {
+        StructuredHosts::Names(names) => names
+            .into_iter()
+            .map(|name| (name, BTreeMap::new()))
+            .collect(),
+        StructuredHosts::WithVars(hosts) => hosts
+            .into_iter()
+            .map(|(name, vars)| {
+                let vars = vars
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(k, v)| (k, v.to_string()))
+                    .collect();
+                (name, vars)
+            })
+            .collect(),
+    };
+    let mut inventory = Inventory::default();
+    inventory.groups.insert(
+        "ungrouped".to_string(),
+        InventoryGroup {
+            hosts: hosts.keys().cloned().collect(),
+            ..Default::default()
+        },
+    );
+    inventory.hosts = hosts;
+    Ok(inventory)
+}
+
+/// Group of a dynamic inventory, either the short list of hosts or the full form.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ScriptGroup {
+    Hosts(Vec<String>),
+    Group {
+        #[serde(default)]
+        hosts: Vec<String>,
+        #[serde(default)]
+        children: Vec<String>,
+        #[serde(default)]
+        vars: BTreeMap<String, serde_json::Value>,
+    },
+}
+
+#[derive(Deserialize, Default)]
+struct ScriptMeta {
+    #[serde(default)]
+    hostvars: BTreeMap<String, BTreeMap<String, serde_json::Value>>,
+}
+
+/// Strings as they are, anything else as JSON.
+fn json_var(value: serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(a) => a,
+        other => other.to_string(),
+    }
+}
+
+/// Runs an Ansible dynamic inventory script with `--list` and parses the JSON it prints.
+pub fn run_inventory_script(path: &Path) -> Result<Inventory, Error> {
+    // a bare file name would be looked up in $PATH instead of the working directory
+    let output = std::process::Command::new(Path::new(".").join(path))
+        .arg("--list")
+        .output()
+        .map_err(|e| {
+            Error::msg(format!(
+                "Failed running inventory script {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+    if !output.status.success() {
+        return Err(Error::msg(format!(
+            "Inventory script {} failed with {}: {}",
+            path.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    parse_script_inventory(&output.stdout).map_err(|e| {
+        Error::msg(format!(
+            "Invalid inventory from script {}: {}",
+            path.display(),
+            e
+        ))
+    })
+}
+
+/// Parses the `--list` output of a dynamic inventory, host variables come from `_meta.hostvars`.
+pub fn parse_script_inventory(json: &[u8]) -> Result<Inventory, Error> {
+    let mut groups: BTreeMap<String, serde_json::Value> = serde_json::from_slice(json)?;
+    let meta: ScriptMeta = match groups.remove("_meta") {
+        Some(meta) => serde_json::from_value(meta)?,
+        None => ScriptMeta::default(),
+    };
+    let mut inventory = Inventory::default();
+    for (name, group) in groups {
+        let (hosts, children, vars) = match serde_json::from_value(group)
+            .map_err(|e| Error::msg(format!("Invalid group {}: {}", name, e)))?
+        {
+            ScriptGroup::Hosts(hosts) => (hosts, Vec::new(), BTreeMap::new()),
+            ScriptGroup::Group {
+                hosts,
+                children,
+                vars,
+            } => (hosts, children, vars),
+        };
+        for host in &hosts {
+            inventory.hosts.entry(host.clone()).or_default();
+        }
+        inventory.groups.insert(
+            name,
+            InventoryGroup {
+                hosts,
+                children,
+                vars: vars.into_iter().map(|(k, v)| (k, json_var(v))).collect(),
+            },
+        );
+    }
+    for (host, vars) in meta.hostvars {
+        if let Some(host_vars) = inventory.hosts.get_mut(&host) {
+            host_vars.extend(vars.into_iter().map(|(k, v)| (k, json_var(v))));
+        }
+    }
+    Ok(inventory)
+}
+
+/// Maps every inventory host to `host:port` with its variables, along with per-host
+/// `ansible_user` and `host_os` overrides.
+pub fn inventory_hosts(inventory: &Inventory, default_port: u16) -> Result<InventoryHosts, Error> {
+    let mut hosts = Vec::new();
+    let mut users = Vec::new();
+    let mut systems = Vec::new();
+    for name in inventory.hosts.keys() {
+        let port = inventory.port(name)?.unwrap_or(default_port);
+        let host = parse_host(name, port)
+            .ok_or_else(|| Error::msg(format!("Invalid inventory host {}", name)))?;
+        if let Some(user) = inventory.user(name) {
+            users.push((host.clone(), user.to_string()));
+        }
+        if let Some(os) = inventory.os(name)? {
+            systems.push((host.clone(), os));
+        }
+        hosts.push((host, inventory.vars(name)));
+    }
+    Ok((hosts, users, systems))
+}
+
+/// Replaces every `{{ name }}` in `template` with the variable's value, `\{{` is a literal
+/// `{{`. Undefined variables are an error, or left as they are with `keep_undefined`.
+pub fn render(
+    template: &str,
+    vars: &BTreeMap<String, String>,
+    keep_undefined: bool,
+) -> Result<String, Error> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        if rest[..start].ends_with('\\') {
+            out.push_str(&rest[..start - 1]);
+            out.push_str("{{");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        out.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find("}}")
+            .map(|e| e + start)
+            .ok_or_else(|| Error::msg(format!("Unclosed {{{{ in {}", template)))?;
+        let name = rest[start + 2..end].trim();
+        match vars.get(name) {
+            Some(value) => out.push_str(value),
+            None if keep_undefined => out.push_str(&rest[start..end + 2]),
+            None => {
+                return Err(Error::msg(format!(
+                    "Undefined variable {} in {}",
+                    name, template
+                )))
+            }
+        }
+        rest = &rest[end + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Groups hostnames by identical output, e.g. to spot the odd ones out of a fleet.
+pub fn aggregate_by_result(
+    responses: impl Iterator<Item = Response>,
+) -> BTreeMap<String, Vec<String>> {
+    let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for response in responses {
+        groups
+            .entry(response.result)
+            .or_default()
+            .push(response.hostname);
+    }
+    groups
+}
+
+/// Streams responses in the given format. `finish` must be called to close JSON arrays.
+pub struct ResponseWriter<W: Write> {
+    writer: W,
+    format: OutputFormat,
+    pretty: bool,
+    written: usize,
+}
+
+impl<W: Write> ResponseWriter<W> {
+    pub fn new(writer: W, format: OutputFormat) -> Self {
+        ResponseWriter {
+            writer,
+            format,
+            pretty: false,
+            written: 0,
+        }
+    }
+
+    /// Pretty prints responses of JSON arrays, other formats are unaffected.
+    pub fn pretty(mut self, pretty: bool) -> Self {
+        self.pretty = pretty;
+        self
+    }
+
+    pub fn write(&mut self, response: &Response) -> Result<(), Error> {
+        let writer = &mut self.writer;
+        match self.format {
+            OutputFormat::Json => {
+                writer.write_all(if self.written == 0 { b"[\n" } else { b",\n" })?;
+                if self.pretty {
+                    serde_json::to_writer_pretty(&mut *writer, response)?;
+                } else {
+                    serde_json::to_writer(&mut *writer, response)?;
+                }
+            }
+            OutputFormat::Jsonl => {
+                serde_json::to_writer(&mut *writer, response)?;
+                writer.write_all(b"\n")?;
+            }
+            OutputFormat::Yaml => {
+                serde_yaml::to_writer(&mut *writer, response)?;
+                writer.write_all(b"\n")?;
+            }
+            OutputFormat::Csv => {
+                let mut wr = csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(writer);
+                if self.written == 0 {
+                    wr.write_record(["hostname", "command", "status", "process_time", "result"])?;
+                }
+                wr.write_record([
+                    response.hostname.as_str(),
+                    response.command.as_str(),
+                    &response.status.to_string(),
+                    &response.process_time.as_secs_f64().to_string(),
+                    response.result.as_str(),
+                ])?;
+                wr.flush()?;
+            }
+        }
+        self.written += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W, Error> {
+        if self.format == OutputFormat::Json {
+            let end: &[u8] = if self.written == 0 { b"[]\n" } else { b"\n]\n" };
+            self.writer.write_all(end)?;
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> OutputSink for ResponseWriter<W> {
+    fn write(&mut self, response: &Response) -> Result<(), Error> {
+        ResponseWriter::write(self, response)
+    }
+
+    fn finalize(self: Box<Self>) -> Result<(), Error> {
+        self.finish().map(drop)
+    }
+}
+
+/// Reads responses saved as a JSON array or JSON lines, decompressing xz and gzip files
+/// recognized by their extension or their magic bytes.
+pub fn read_responses(path: &Path) -> Result<Vec<Response>, Error> {
+    const XZ_MAGIC: [u8; 6] = [0xfd, b'7', b'z', b'X', b'Z', 0];
+    const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+    let mut raw = Vec::new();
+    File::open(path)?.read_to_end(&mut raw)?;
+    let extension = path.extension().and_then(|e| e.to_str());
+    let mut content = String::new();
+    if extension == Some("xz") || raw.starts_with(&XZ_MAGIC) {
+        XzDecoder::new(raw.as_slice()).read_to_string(&mut content)?;
+    } else if extension == Some("gz") || raw.starts_with(&GZIP_MAGIC) {
+        GzDecoder::new(raw.as_slice()).read_to_string(&mut content)?;
+    } else {
+        content = String::from_utf8(raw)?;
+    }
+    if content.trim_start().starts_with('[') {
+        return Ok(serde_json::from_str(&content)?);
     }
-    Ok(map)
+    serde_json::Deserializer::from_str(&content)
+        .into_iter()
+        .map(|r| r.map_err(Error::from))
+        .collect()
+}
+
+/// Drops hosts that succeeded in `prev`, keeping failed and never attempted ones.
+pub fn filter_already_succeeded(
+    hosts: Vec<(String, String)>,
+    prev: &[Response],
+) -> Vec<(String, String)> {
+    let succeeded: BTreeSet<&str> = prev
+        .iter()
+        .filter(|r| r.status)
+        .map(|r| r.hostname.as_str())
+        .collect();
+    hosts
+        .into_iter()
+        .filter(|(host, _)| !succeeded.contains(host.as_str()))
+        .collect()
 }
 
 pub fn get_config(path: &Path) -> Config {
     let f = match fs::read_to_string(path) {
         Ok(a) => a,
         Err(e) => {
-            eprintln!("Failed reading config. Using default values : {}", e);
+            warn!("Failed reading config. Using default values : {}", e);
             return Config::default();
         }
     };
     match toml::from_str(f.as_str()) {
         Ok(t) => t,
         Err(e) => {
-            eprintln!("Error parsing config:{}", e);
+            error!("Error parsing config:{}", e);
             Config::default()
         }
     }
 }
 
-pub fn save_to_file(conf: &Config, data: Vec<Response>) {
-    let filename = match &conf.output.filename {
-        None => {
-            eprintln!("Filename to save is not given. Printing to stdout.");
-            save_to_console(&conf, &data);
-            return;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_names_get_the_default_port() {
+        assert_eq!(parse_host("10.0.0.1", 22).as_deref(), Some("10.0.0.1:22"));
+        assert_eq!(
+            parse_host("web-1.example.com", 22).as_deref(),
+            Some("web-1.example.com:22")
+        );
+        assert_eq!(parse_host("db", 22).as_deref(), Some("db:22"));
+    }
+
+    #[test]
+    fn invalid_host_names_are_rejected() {
+        assert_eq!(parse_host("", 22), None);
+        assert_eq!(parse_host("bad host", 22), None);
+        assert_eq!(parse_host("-web.example.com", 22), None);
+        assert_eq!(parse_host("web..example.com", 22), None);
+        assert_eq!(parse_host("under_score.example.com", 22), None);
+        assert_eq!(parse_host(&"a".repeat(64), 22), None);
+    }
+
+    #[test]
+    fn ipv6_hosts_are_bracketed() {
+        assert_eq!(parse_host("::1", 22).as_deref(), Some("[::1]:22"));
+        assert_eq!(parse_host("[::1]", 2222).as_deref(), Some("[::1]:2222"));
+        assert_eq!(
+            parse_host("[fe80::1]:22", 2222).as_deref(),
+            Some("[fe80::1]:22")
+        );
+        assert_eq!(
+            parse_host("2001:db8::1", 22).as_deref(),
+            Some("[2001:db8::1]:22")
+        );
+    }
+
+    /// Writes `content` to a file of the temp dir unique to this process.
+    fn temp_file(name: &str, content: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("ansible-rs-{}-{}", std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn hosts_keep_their_own_port() {
+        assert_eq!(
+            parse_host("10.0.0.1:2222", 22).as_deref(),
+            Some("10.0.0.1:2222")
+        );
+        assert_eq!(parse_host("web:2222", 22).as_deref(), Some("web:2222"));
+        assert_eq!(parse_host("web:ssh", 22), None);
+    }
+
+    #[test]
+    fn csv_hosts_take_the_port_column() {
+        let path = temp_file(
+            "ports.csv",
+            b"host,command,port\n10.0.0.1,uptime,2222\nweb,id,\ndb,id,bad\n",
+        );
+        let hosts = generate_kv_hosts_from_csv(path.to_str().unwrap(), 22).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(
+            hosts,
+            vec![
+                ("10.0.0.1:2222".to_string(), "uptime".to_string()),
+                ("web:22".to_string(), "id".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn cidr_blocks_skip_network_and_broadcast() {
+        let hosts = expand_cidr("192.168.1.77/30", false).unwrap();
+        assert_eq!(
+            hosts,
+            vec![
+                Ipv4Addr::new(192, 168, 1, 77),
+                Ipv4Addr::new(192, 168, 1, 78)
+            ]
+        );
+        assert_eq!(expand_cidr("10.0.0.0/24", false).unwrap().len(), 254);
+        assert_eq!(
+            expand_cidr("10.0.0.5/32", false).unwrap(),
+            vec![Ipv4Addr::new(10, 0, 0, 5)]
+        );
+        assert_eq!(expand_cidr("10.0.0.4/31", false).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn large_cidr_blocks_need_to_be_allowed() {
+        assert_eq!(expand_cidr("10.0.0.0/16", false).unwrap().len(), 1 << 16);
+        assert!(expand_cidr("10.0.0.0/15", false).is_err());
+        assert_eq!(expand_cidr("10.0.0.0/15", true).unwrap().len(), 1 << 17);
+    }
+
+    #[test]
+    fn malformed_cidr_blocks_are_rejected() {
+        assert!(expand_cidr("10.0.0.0", false).is_err());
+        assert!(expand_cidr("10.0.0/24", false).is_err());
+        assert!(expand_cidr("10.0.0.0/33", false).is_err());
+        assert!(expand_cidr("10.0.0.0/x", false).is_err());
+    }
+
+    #[test]
+    fn host_lists_are_read_from_any_reader() {
+        let input = "10.0.0.1\n\n'web'\n\"db:2222\"\nbad host\n10.0.1.0/30\n";
+        let hosts = parse_hosts(input.as_bytes(), &Config::default()).unwrap();
+        assert_eq!(
+            hosts,
+            vec![
+                "10.0.0.1:22",
+                "web:22",
+                "db:2222",
+                "10.0.1.1:22",
+                "10.0.1.2:22"
+            ]
+        );
+    }
+
+    const INI: &str = "\
+        # comment\n\
+        bastion.example.com\n\
+        \n\
+        [web]\n\
+        web1 ansible_port=2222 ansible_user='deploy'\n\
+        web2\n\
+        \n\
+        [db]\n\
+        db1 host_os=windows\n\
+        \n\
+        [prod:children]\n\
+        web\n\
+        db\n";
+
+    /// Parses `content` from a file named after its hash, tests run in parallel.
+    fn ini(content: &str) -> Result<Inventory, Error> {
+        use std::hash::{Hash, Hasher};
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        content.hash(&mut hasher);
+        let path = temp_file(&format!("{:x}.ini", hasher.finish()), content.as_bytes());
+        let inventory = parse_ini_inventory(&path);
+        fs::remove_file(path).unwrap();
+        inventory
+    }
+
+    #[test]
+    fn ini_inventories_have_groups_and_children() {
+        let inventory = ini(INI).unwrap();
+        assert_eq!(
+            inventory.hosts.keys().collect::<Vec<_>>(),
+            vec!["bastion.example.com", "db1", "web1", "web2"]
+        );
+        assert_eq!(
+            inventory.groups["ungrouped"].hosts,
+            vec!["bastion.example.com"]
+        );
+        assert_eq!(
+            inventory
+                .group_hosts("prod")
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec!["db1", "web1", "web2"]
+        );
+        let counts: Vec<_> = inventory.group_counts().into_iter().collect();
+        assert_eq!(
+            counts,
+            vec![
+                ("db".to_string(), 1),
+                ("prod".to_string(), 3),
+                ("ungrouped".to_string(), 1),
+                ("web".to_string(), 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn ini_host_variables_set_port_user_and_os() {
+        let (hosts, users, systems) = inventory_hosts(&ini(INI).unwrap(), 22).unwrap();
+        let names: Vec<_> = hosts.iter().map(|(h, _)| h.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["bastion.example.com:22", "db1:22", "web1:2222", "web2:22"]
+        );
+        assert_eq!(users, vec![("web1:2222".to_string(), "deploy".to_string())]);
+        assert_eq!(systems, vec![("db1:22".to_string(), HostOs::Windows)]);
+    }
+
+    #[test]
+    fn malformed_ini_inventories_are_rejected() {
+        assert!(ini("[web\nweb1\n").is_err());
+        assert!(ini("[web:hosts]\nweb1\n").is_err());
+        assert!(ini("web1 ansible_port\n").is_err());
+        let inventory = ini("web1 ansible_port=ssh\n").unwrap();
+        assert!(inventory_hosts(&inventory, 22).is_err());
+    }
+
+    fn matches(limit: &str, host: &str) -> bool {
+        HostPattern::parse_list(limit)
+            .unwrap()
+            .iter()
+            .any(|p| p.matches(host))
+    }
+
+    #[test]
+    fn limit_patterns_match_with_or_without_the_port() {
+        assert!(matches("web*", "web1:22"));
+        assert!(matches("web1:22", "web1:22"));
+        assert!(matches("db*, web*", "web1:22"));
+        assert!(matches("~^db[0-9]+$", "db12:22"));
+        assert!(matches("::1", "[::1]:22"));
+        assert!(!matches("~^db[0-9]+$", "db1a:22"));
+        assert!(!matches("web", "web1:22"));
+        assert!(HostPattern::parse_list("~db(").is_err());
+    }
+
+    #[test]
+    fn inventories_are_limited_by_host_or_group() {
+        let mut inventory = ini(INI).unwrap();
+        inventory.limit(&HostPattern::parse_list("db,bastion*").unwrap());
+        assert_eq!(
+            inventory.hosts.keys().collect::<Vec<_>>(),
+            vec!["bastion.example.com", "db1"]
+        );
+        let mut inventory = ini(INI).unwrap();
+        inventory.limit(&HostPattern::parse_list("prod").unwrap());
+        assert_eq!(
+            inventory.hosts.keys().collect::<Vec<_>>(),
+            vec!["db1", "web1", "web2"]
+        );
+    }
+
+    #[test]
+    fn host_vars_override_group_vars() {
+        let inventory = ini("\
+            [all:vars]\n\
+            env=dev\n\
+            role=none\n\
+            [web]\n\
+            web1 role=frontend\n\
+            web2\n\
+            [web:vars]\n\
+            role=web\n\
+            tier = \"front\"\n\
+            [prod:children]\n\
+            web\n\
+            [prod:vars]\n\
+            env=prod\n\
+            tier=any\n")
+        .unwrap();
+        let vars = inventory.vars("web1");
+        assert_eq!(vars["inventory_hostname"], "web1");
+        assert_eq!(vars["env"], "prod");
+        assert_eq!(vars["role"], "frontend");
+        assert_eq!(vars["tier"], "front");
+        assert_eq!(inventory.vars("web2")["role"], "web");
+    }
+
+    #[test]
+    fn templates_are_rendered_with_host_vars() {
+        let vars: BTreeMap<String, String> = vec![
+            ("name".to_string(), "web1".to_string()),
+            ("port".to_string(), "80".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            render("curl {{ name }}:{{port}}/", &vars, false).unwrap(),
+            "curl web1:80/"
+        );
+        assert_eq!(
+            render("echo \\{{ name }} {{ name }}", &vars, false).unwrap(),
+            "echo {{ name }} web1"
+        );
+        assert!(render("echo {{ missing }}", &vars, false).is_err());
+        assert_eq!(
+            render("echo {{ missing }}", &vars, true).unwrap(),
+            "echo {{ missing }}"
+        );
+        assert!(render("echo {{ name", &vars, false).is_err());
+    }
+
+    #[test]
+    fn json_host_lists_are_ungrouped_hosts() {
+        let path = temp_file("hosts.json", br#"["web1", "10.0.0.1:2222"]"#);
+        let inventory = parse_structured_inventory(&path, false).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(
+            inventory.groups["ungrouped"].hosts,
+            vec!["10.0.0.1:2222", "web1"]
+        );
+        let (hosts, _, _) = inventory_hosts(&inventory, 22).unwrap();
+        let names: Vec<_> = hosts.iter().map(|(h, _)| h.as_str()).collect();
+        assert_eq!(names, vec!["10.0.0.1:2222", "web1:22"]);
+    }
+
+    #[test]
+    fn yaml_hosts_carry_their_variables() {
+        let path = temp_file(
+            "hosts.yaml",
+            b"web1:\n  ansible_port: 2222\n  primary: true\n  weight: 0.5\ndb1:\n",
+        );
+        let inventory = parse_structured_inventory(&path, true).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(inventory.port("web1").unwrap(), Some(2222));
+        assert_eq!(inventory.hosts["web1"]["primary"], "true");
+        assert_eq!(inventory.hosts["web1"]["weight"], "0.5");
+        assert!(inventory.hosts["db1"].is_empty());
+    }
+
+    #[test]
+    fn malformed_structured_inventories_are_rejected() {
+        let path = temp_file("bad-hosts.json", br#"{"web1": [1, 2]}"#);
+        assert!(parse_structured_inventory(&path, false).is_err());
+        fs::remove_file(path).unwrap();
+        assert!(parse_structured_inventory(Path::new("/nonexistent/hosts.json"), false).is_err());
+    }
+
+    const SCRIPT_INVENTORY: &str = r#"{
+        "web": {"hosts": ["web1", "web2"], "vars": {"role": "web", "replicas": 2}},
+        "db": ["db1"],
+        "prod": {"children": ["web", "db"]},
+        "_meta": {"hostvars": {"web1": {"ansible_port": 2222}, "unknown": {"a": "b"}}}
+    }"#;
+
+    #[test]
+    fn script_inventories_read_groups_and_hostvars() {
+        let inventory = parse_script_inventory(SCRIPT_INVENTORY.as_bytes()).unwrap();
+        assert_eq!(
+            inventory.hosts.keys().collect::<Vec<_>>(),
+            vec!["db1", "web1", "web2"]
+        );
+        assert_eq!(inventory.port("web1").unwrap(), Some(2222));
+        assert_eq!(inventory.vars("web2")["replicas"], "2");
+        assert_eq!(inventory.group_hosts("prod").len(), 3);
+        assert!(parse_script_inventory(br#"{"web": 1}"#).is_err());
+        assert!(parse_script_inventory(b"not json").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn inventory_scripts_are_run_with_list() {
+        use std::os::unix::fs::PermissionsExt;
+        let script = format!(
+            "#!/bin/sh\n[ \"$1\" = --list ] || exit 3\ncat <<'EOF'\n{}\nEOF\n",
+            SCRIPT_INVENTORY
+        );
+        let path = temp_file("inventory.sh", script.as_bytes());
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        let inventory = run_inventory_script(&path);
+        let failing = temp_file(
+            "failing-inventory.sh",
+            b"#!/bin/sh\necho oops >&2\nexit 1\n",
+        );
+        fs::set_permissions(&failing, fs::Permissions::from_mode(0o755)).unwrap();
+        let error = run_inventory_script(&failing).unwrap_err().to_string();
+        fs::remove_file(path).unwrap();
+        fs::remove_file(failing).unwrap();
+        assert_eq!(inventory.unwrap().hosts.len(), 3);
+        assert!(error.ends_with("oops"), "{}", error);
+    }
+
+    #[test]
+    fn config_validation_reports_every_error() {
+        assert_eq!(Config::default().validate(), Ok(()));
+        let config = Config {
+            threads: 0,
+            agent_parallelism: 0,
+            timeout: 0,
+            output: OutputProps {
+                save_to_file: true,
+                compression_level: 10,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let errors = config.validate().unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                "threads must be greater than 0",
+                "agent_parallelism must be at least 1",
+                "timeout must be greater than 0",
+                "output.filename must be set when output.save_to_file is true",
+                "output.compression_level must be between 0 and 9",
+            ]
+        );
+    }
+
+    #[test]
+    fn config_files_are_validated_as_toml() {
+        let config: Config = toml::from_str(
+            "threads = 4\nagent_parallelism = 2\ncommand = \"id\"\ntimeout = 0\n\
+             [output]\nsave_to_file = false\npretty_format = false\nshow_progress = false\n",
+        )
+        .unwrap();
+        assert_eq!(config.default_port, 22);
+        assert_eq!(
+            config.validate(),
+            Err(vec!["timeout must be greater than 0".to_string()])
+        );
+    }
+
+    fn response(hostname: &str, result: &str, status: bool) -> Response {
+        Response {
+            result: result.to_string(),
+            result_base64: false,
+            stderr: String::new(),
+            hostname: hostname.to_string(),
+            address: None,
+            command: "uptime".to_string(),
+            process_time: std::time::Duration::from_millis(1500),
+            status,
+            exit_code: if status { 0 } else { 1 },
+            attempts: 1,
+            failure_kind: None,
+            truncated: false,
+            matches_baseline: None,
+            timings: None,
+            metadata: Default::default(),
         }
-        Some(a) => Path::new(a.as_str()),
-    };
+    }
 
-    let file = match File::create(filename) {
-        Ok(a) => a,
-        Err(e) => {
-            eprintln!("Erorr saving content to file:{}", e);
-            save_to_console(&conf, &data);
-            return;
+    fn written(format: OutputFormat, pretty: bool, responses: &[Response]) -> String {
+        let mut writer = ResponseWriter::new(Vec::new(), format).pretty(pretty);
+        for response in responses {
+            writer.write(response).unwrap();
         }
-    };
-    if conf.output.pretty_format {
-        match serde_json::to_writer_pretty(file, &data) {
-            Ok(_) => println!("Saved successfully"),
-            Err(e) => eprintln!("Error saving: {}", e),
-        };
-    } else {
-        match serde_json::to_writer(file, &data) {
-            Ok(_) => println!("Saved successfully"),
-            Err(e) => eprintln!("Error saving: {}", e),
+        String::from_utf8(writer.finish().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn json_output_is_a_valid_array() {
+        assert_eq!(written(OutputFormat::Json, false, &[]), "[]\n");
+        let responses = [
+            response("web1:22", "up", true),
+            response("web2:22", "down", false),
+        ];
+        let output = written(OutputFormat::Json, false, &responses);
+        let parsed: Vec<Response> = serde_json::from_str(&output).unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[1].hostname, "web2:22");
+        assert_eq!(output.lines().count(), 4);
+    }
+
+    #[test]
+    fn jsonl_output_has_a_response_per_line() {
+        let responses = [
+            response("web1:22", "a\nb", true),
+            response("web2:22", "", false),
+        ];
+        let output = written(OutputFormat::Jsonl, false, &responses);
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let first: Response = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(first.result, "a\nb");
+    }
+
+    #[test]
+    fn yaml_output_is_a_stream_of_documents() {
+        let responses = [
+            response("web1:22", "up", true),
+            response("web2:22", "down", false),
+        ];
+        let output = written(OutputFormat::Yaml, false, &responses);
+        let parsed: Vec<Response> = output
+            .split("---")
+            .filter(|d| !d.trim().is_empty())
+            .map(|d| serde_yaml::from_str(d).unwrap())
+            .collect();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0].result, "up");
+    }
+
+    #[test]
+    fn csv_output_has_one_header() {
+        let responses = [
+            response("web1:22", "a,\"b\"", true),
+            response("web2:22", "", false),
+        ];
+        assert_eq!(
+            written(OutputFormat::Csv, false, &responses),
+            "hostname,command,status,process_time,result\n\
+             web1:22,uptime,true,1.5,\"a,\"\"b\"\"\"\n\
+             web2:22,uptime,false,1.5,\n"
+        );
+    }
+
+    #[test]
+    fn pretty_format_only_affects_json() {
+        let responses = [response("web1:22", "up", true)];
+        let pretty = written(OutputFormat::Json, true, &responses);
+        assert!(pretty.contains("\n  \"result\": \"up\",\n"));
+        let parsed: Vec<Response> = serde_json::from_str(&pretty).unwrap();
+        assert_eq!(parsed[0].hostname, "web1:22");
+        assert_eq!(
+            written(OutputFormat::Jsonl, true, &responses),
+            written(OutputFormat::Jsonl, false, &responses)
+        );
+    }
+
+    #[test]
+    fn responses_record_their_command() {
+        let mut response = response("web1:22", "up", true);
+        response.command = "systemctl is-active nginx".to_string();
+        let output = written(OutputFormat::Jsonl, false, &[response]);
+        let parsed: Response = serde_json::from_str(output.trim()).unwrap();
+        assert_eq!(parsed.command, "systemctl is-active nginx");
+        // saved by versions before the command was recorded
+        let old: Response = serde_json::from_str(
+            r#"{"result": "up", "stderr": "", "hostname": "web1:22",
+                "process_time": {"secs": 1, "nanos": 0}, "status": true,
+                "exit_code": 0, "attempts": 1, "failure_kind": null}"#,
+        )
+        .unwrap();
+        assert_eq!(old.command, "");
+    }
+
+    #[test]
+    fn xz_results_are_read_back() {
+        let responses = [
+            response("web1:22", "up", true),
+            response("web2:22", "down", false),
+        ];
+        let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+        encoder
+            .write_all(written(OutputFormat::Json, true, &responses).as_bytes())
+            .unwrap();
+        let compressed = encoder.finish().unwrap();
+        // recognized by the extension as well as by the magic bytes
+        for name in &["results.json.xz", "results-xz"] {
+            let path = temp_file(name, &compressed);
+            let read = read_responses(&path).unwrap();
+            fs::remove_file(path).unwrap();
+            assert_eq!(read.len(), 2);
+            assert_eq!(read[1].result, "down");
         }
     }
-}
 
-pub fn save_to_console(conf: &Config, data: &Vec<Response>) {
-    if conf.output.pretty_format {
-        println!("{}", serde_json::to_string_pretty(&data).unwrap())
-    } else {
-        println!("{}", serde_json::to_string(&data).unwrap())
+    #[test]
+    fn compress_is_the_same_as_xz_compression() {
+        let mut output = OutputProps::default();
+        assert_eq!(output.compression(), CompressionFormat::None);
+        output.compress = true;
+        assert_eq!(output.compression(), CompressionFormat::Xz);
+        output.compression = CompressionFormat::Gzip;
+        assert_eq!(output.compression(), CompressionFormat::Gzip);
+    }
+
+    #[test]
+    fn gzip_results_are_read_back() {
+        let responses = [response("web1:22", "up", true)];
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder
+            .write_all(written(OutputFormat::Jsonl, false, &responses).as_bytes())
+            .unwrap();
+        let compressed = encoder.finish().unwrap();
+        for name in &["results.jsonl.gz", "results-gz"] {
+            let path = temp_file(name, &compressed);
+            let read = read_responses(&path).unwrap();
+            fs::remove_file(path).unwrap();
+            assert_eq!(read.len(), 1);
+            assert_eq!(read[0].hostname, "web1:22");
+        }
+    }
+
+    #[test]
+    fn hosts_are_grouped_by_output() {
+        let responses = vec![
+            response("web1:22", "5.4", true),
+            response("web2:22", "5.10", true),
+            response("web3:22", "5.4", true),
+        ];
+        let groups: Vec<_> = aggregate_by_result(responses.into_iter())
+            .into_iter()
+            .collect();
+        assert_eq!(
+            groups,
+            vec![
+                ("5.10".to_string(), vec!["web2:22".to_string()]),
+                (
+                    "5.4".to_string(),
+                    vec!["web1:22".to_string(), "web3:22".to_string()]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn only_hosts_that_succeeded_are_skipped() {
+        let hosts = ["web1:22", "web2:22", "web3:22"]
+            .iter()
+            .map(|h| (h.to_string(), "uptime".to_string()))
+            .collect();
+        let prev = [
+            response("web1:22", "up", true),
+            response("web2:22", "down", false),
+        ];
+        let path = temp_file(
+            "previous.jsonl",
+            written(OutputFormat::Jsonl, false, &prev).as_bytes(),
+        );
+        let prev = read_responses(&path).unwrap();
+        fs::remove_file(path).unwrap();
+        let left: Vec<_> = filter_already_succeeded(hosts, &prev)
+            .into_iter()
+            .map(|(h, _)| h)
+            .collect();
+        assert_eq!(left, vec!["web2:22", "web3:22"]);
+    }
+
+    #[test]
+    fn incremental_files_go_in_a_dated_folder() {
+        let base =
+            std::env::temp_dir().join(format!("ansible-rs-incremental-{}", std::process::id()));
+        let output = OutputProps {
+            incremental_dir: Some(base.clone()),
+            ..OutputProps::default()
+        };
+        let dir = output.incremental_dir().unwrap();
+        assert!(dir.is_dir());
+        assert_eq!(dir.parent(), Some(base.as_path()));
+        fs::remove_dir_all(&base).unwrap();
+
+        let file = temp_file("not-a-dir", b"");
+        let mut config = Config::default();
+        config.output.incremental_dir = Some(file.clone());
+        let output = config.output.clone();
+        assert!(output.incremental_dir().is_err());
+        assert_eq!(config.validate().unwrap_err().len(), 1);
+        fs::remove_file(file).unwrap();
     }
 }
@@ -0,0 +1,84 @@
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Ordered steps run on every host over a single session.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Playbook {
+    pub steps: Vec<Step>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Step {
+    pub name: String,
+    pub command: String,
+    /// Command run before the step, the step is skipped unless it exits with 0.
+    #[serde(default)]
+    pub when: Option<String>,
+    /// Keep running the following steps if this one fails.
+    #[serde(default)]
+    pub ignore_errors: bool,
+}
+
+impl Playbook {
+    /// Reads a playbook from YAML, or from TOML if the extension is `.toml`.
+    pub fn load(path: &Path) -> Result<Playbook, Error> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| Error::msg(format!("Failed reading {}: {}", path.display(), e)))?;
+        let playbook: Playbook = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&content)?,
+            _ => serde_yaml::from_str(&content)?,
+        };
+        if playbook.steps.is_empty() {
+            return Err(Error::msg(format!("{} has no steps", path.display())));
+        }
+        Ok(playbook)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loaded(name: &str, content: &str) -> Result<Playbook, Error> {
+        let dir = std::env::temp_dir().join(format!("ansible-rs-playbook-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, content).unwrap();
+        let playbook = Playbook::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        playbook
+    }
+
+    #[test]
+    fn playbooks_load_from_yaml_and_toml() {
+        let yaml = loaded(
+            "site.yml",
+            "steps:\n  - name: restart\n    command: systemctl restart app\n    when: test -f /etc/app.conf\n    ignore_errors: true\n",
+        )
+        .unwrap();
+        let step = &yaml.steps[0];
+        assert_eq!(step.name, "restart");
+        assert_eq!(step.command, "systemctl restart app");
+        assert_eq!(step.when.as_deref(), Some("test -f /etc/app.conf"));
+        assert!(step.ignore_errors);
+        let toml = loaded(
+            "site.toml",
+            "[[steps]]\nname = \"uptime\"\ncommand = \"uptime\"\n\n[[steps]]\nname = \"df\"\ncommand = \"df -h\"\n",
+        )
+        .unwrap();
+        let commands: Vec<_> = toml.steps.iter().map(|s| s.command.as_str()).collect();
+        assert_eq!(commands, ["uptime", "df -h"]);
+        assert_eq!(toml.steps[0].when, None);
+        assert!(!toml.steps[0].ignore_errors);
+    }
+
+    #[test]
+    fn playbooks_need_steps() {
+        let e = loaded("empty.yml", "steps: []\n").unwrap_err();
+        assert!(e.to_string().ends_with("empty.yml has no steps"));
+        let missing = std::env::temp_dir().join("ansible-rs-no-playbook-here.yml");
+        let e = Playbook::load(&missing).unwrap_err();
+        assert!(e.to_string().starts_with("Failed reading "));
+    }
+}
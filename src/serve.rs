@@ -0,0 +1,182 @@
+use crate::misc::parse_host;
+use ansible_rs::ParallelSshPropsBuilder;
+use anyhow::Error;
+use log::{error, warn};
+use serde::Deserialize;
+use serde_json::json;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread::spawn;
+
+#[derive(Deserialize, Debug)]
+struct ServeRequest {
+    #[serde(default)]
+    token: String,
+    hosts: Vec<String>,
+    command: String,
+}
+
+/// Accepts newline delimited JSON requests like
+/// `{"token": "...", "hosts": [...], "command": "..."}`, answering each with its responses
+/// as JSON lines. A connection can send several requests, one with a wrong token closes it.
+///
+/// Requests run arbitrary commands on any host with the server's credentials, e.g. its
+/// ssh-agent, so the token must stay secret. The server only listens on loopback addresses
+/// unless `allow_remote` is set, and doesn't encrypt anything.
+pub fn serve(
+    address: &str,
+    builder: &ParallelSshPropsBuilder,
+    default_port: u16,
+    token: &str,
+    allow_remote: bool,
+) -> Result<(), Error> {
+    let listener = bind(address, allow_remote)?;
+    eprintln!("Listening on {}", listener.local_addr()?);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(a) => a,
+            Err(e) => {
+                error!("Failed accepting connection: {}", e);
+                continue;
+            }
+        };
+        let builder = builder.clone();
+        let token = token.to_string();
+        spawn(move || {
+            if let Err(e) = handle_connection(stream, &builder, default_port, &token) {
+                warn!("Connection closed: {}", e)
+            }
+        });
+    }
+    Ok(())
+}
+
+fn bind(address: &str, allow_remote: bool) -> Result<TcpListener, Error> {
+    let listener = TcpListener::bind(address)?;
+    let local = listener.local_addr()?;
+    if !allow_remote && !local.ip().is_loopback() {
+        return Err(Error::msg(format!(
+            "Refusing to listen on {}, anyone reaching it with the token runs commands as you, \
+             pass --allow-remote to listen beyond loopback",
+            local
+        )));
+    }
+    Ok(listener)
+}
+
+/// Compares in constant time, so response times don't leak how much of a token matched.
+fn token_matches(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    builder: &ParallelSshPropsBuilder,
+    default_port: u16,
+    token: &str,
+) -> Result<(), Error> {
+    let mut writer = BufWriter::new(stream.try_clone()?);
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request: ServeRequest = match serde_json::from_str(&line) {
+            Ok(a) => a,
+            Err(e) => {
+                writeln!(writer, "{}", json!({ "error": e.to_string() }))?;
+                writer.flush()?;
+                continue;
+            }
+        };
+        if !token_matches(&request.token, token) {
+            writeln!(writer, "{}", json!({ "error": "Invalid token" }))?;
+            writer.flush()?;
+            return Err(Error::msg("invalid token"));
+        }
+        let mut hosts = Vec::with_capacity(request.hosts.len());
+        for host in &request.hosts {
+            match parse_host(host, default_port) {
+                Some(h) => hosts.push((h, request.command.clone())),
+                None => writeln!(
+                    writer,
+                    "{}",
+                    json!({ "error": format!("Invalid host {}", host) })
+                )?,
+            }
+        }
+        let (rx, props) = builder.build().map_err(Error::msg)?;
+        // props is dropped once every host is processed, which ends the loop below
+        spawn(move || props.parallel_ssh_process(hosts));
+        for response in rx {
+            serde_json::to_writer(&mut writer, &response)?;
+            writer.write_all(b"\n")?;
+            writer.flush()?;
+        }
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    /// Sends `requests` over one connection and returns everything the server answered.
+    fn exchange(requests: &str) -> String {
+        let listener = bind("127.0.0.1:0", false).unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(stream, &ParallelSshPropsBuilder::default(), 22, "secret")
+        });
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(requests.as_bytes()).unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut answer = String::new();
+        client.read_to_string(&mut answer).unwrap();
+        let _ = server.join().unwrap();
+        answer
+    }
+
+    #[test]
+    fn tokens_are_compared_whole() {
+        assert!(token_matches("secret", "secret"));
+        assert!(!token_matches("secre", "secret"));
+        assert!(!token_matches("secrets", "secret"));
+        assert!(!token_matches("", "secret"));
+    }
+
+    #[test]
+    fn only_loopback_is_listened_on_by_default() {
+        assert!(bind("0.0.0.0:0", false).is_err());
+        assert!(bind("127.0.0.1:0", false).is_ok());
+        assert!(bind("0.0.0.0:0", true).is_ok());
+    }
+
+    #[test]
+    fn requests_without_the_token_close_the_connection() {
+        let answer = exchange(
+            "{\"hosts\": [], \"command\": \"id\"}\n\
+             {\"token\": \"secret\", \"hosts\": [\"bad host\"], \"command\": \"id\"}\n",
+        );
+        assert_eq!(answer, "{\"error\":\"Invalid token\"}\n");
+    }
+
+    #[test]
+    fn requests_with_the_token_are_answered() {
+        let answer = exchange(
+            "not json\n{\"token\": \"secret\", \"hosts\": [\"bad host\"], \"command\": \"id\"}\n",
+        );
+        let lines: Vec<&str> = answer.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("{\"error\":"));
+        assert_eq!(lines[1], "{\"error\":\"Invalid host bad host\"}");
+    }
+}
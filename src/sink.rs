@@ -0,0 +1,159 @@
+use crate::Response;
+use anyhow::Error;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Destination of responses, e.g. a file, a database or a message queue.
+pub trait OutputSink {
+    fn write(&mut self, response: &Response) -> Result<(), Error>;
+    /// Called once after the last response to flush and close the output.
+    fn finalize(self: Box<Self>) -> Result<(), Error>;
+}
+
+/// Prints a JSON line per response.
+pub struct StdoutSink;
+
+impl OutputSink for StdoutSink {
+    fn write(&mut self, response: &Response) -> Result<(), Error> {
+        let stdout = std::io::stdout();
+        let mut lock = stdout.lock();
+        serde_json::to_writer(&mut lock, response)?;
+        lock.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn finalize(self: Box<Self>) -> Result<(), Error> {
+        std::io::stdout().flush()?;
+        Ok(())
+    }
+}
+
+/// Discards responses, e.g. when only the progress or the aggregated summary matters.
+pub struct NullSink;
+
+impl OutputSink for NullSink {
+    fn write(&mut self, _response: &Response) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn finalize(self: Box<Self>) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Writes each response pretty printed to `<dir>/<hostname>.json`.
+///
+/// Characters that aren't safe in file names are replaced with `_`, hosts ending up with
+/// the same name get a `_2`, `_3`, ... suffix instead of overwriting each other.
+pub struct PerHostFileSink {
+    dir: PathBuf,
+    used: HashSet<String>,
+}
+
+impl PerHostFileSink {
+    pub fn create(dir: &Path) -> Result<Self, Error> {
+        std::fs::create_dir_all(dir)?;
+        Ok(PerHostFileSink {
+            dir: dir.to_path_buf(),
+            used: HashSet::new(),
+        })
+    }
+
+    fn file_name(&mut self, hostname: &str) -> String {
+        let base: String = hostname
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+                _ => '_',
+            })
+            .collect();
+        // `.` and `..` aren't files
+        let base = if base.chars().all(|c| c == '.') {
+            base.replace('.', "_")
+        } else {
+            base
+        };
+        let mut name = base.clone();
+        let mut n = 1;
+        while !self.used.insert(name.clone()) {
+            n += 1;
+            name = format!("{}_{}", base, n);
+        }
+        name
+    }
+}
+
+impl OutputSink for PerHostFileSink {
+    fn write(&mut self, response: &Response) -> Result<(), Error> {
+        let name = self.file_name(&response.hostname);
+        let path = self.dir.join(format!("{}.json", name));
+        let mut file = BufWriter::new(File::create(&path)?);
+        serde_json::to_writer_pretty(&mut file, response)?;
+        file.write_all(b"\n")?;
+        file.flush()?;
+        Ok(())
+    }
+
+    fn finalize(self: Box<Self>) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn response(hostname: &str, result: &str) -> Response {
+        Response::failure(
+            hostname.to_string(),
+            "uptime".to_string(),
+            result.to_string(),
+            Duration::from_secs(1),
+            1,
+        )
+    }
+
+    #[test]
+    fn every_host_gets_its_own_file() {
+        let dir = std::env::temp_dir().join(format!("ansible-rs-per-host-{}", std::process::id()));
+        let mut sink: Box<dyn OutputSink> = Box::new(PerHostFileSink::create(&dir).unwrap());
+        for (host, result) in [
+            ("web1:22", "up 1 day"),
+            ("web2:22", "up 2 days"),
+            ("db:22", "down"),
+        ] {
+            sink.write(&response(host, result)).unwrap();
+        }
+        sink.finalize().unwrap();
+        let mut files: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["db_22.json", "web1_22.json", "web2_22.json"]);
+        let saved = std::fs::read_to_string(dir.join("web2_22.json")).unwrap();
+        let saved: Response = serde_json::from_str(&saved).unwrap();
+        assert_eq!(
+            (saved.hostname.as_str(), saved.result.as_str()),
+            ("web2:22", "up 2 days")
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn host_file_names_are_safe_and_unique() {
+        let mut sink = PerHostFileSink {
+            dir: PathBuf::new(),
+            used: HashSet::new(),
+        };
+        assert_eq!(sink.file_name("../etc/passwd"), ".._etc_passwd");
+        assert_eq!(sink.file_name("web1:22"), "web1_22");
+        assert_eq!(sink.file_name("web1/22"), "web1_22_2");
+        assert_eq!(sink.file_name("web1?22"), "web1_22_3");
+        assert_eq!(sink.file_name(".."), "__");
+        assert_eq!(sink.file_name("."), "_");
+    }
+}
@@ -0,0 +1,265 @@
+use crate::REDACTED;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+/// SOCKS5 proxy all outbound connections go through.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SocksProxy {
+    pub address: SocketAddr,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+impl std::fmt::Debug for SocksProxy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SocksProxy")
+            .field("address", &self.address)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| REDACTED))
+            .finish()
+    }
+}
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const USER_PASS: u8 = 2;
+const CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+fn proxy_error(msg: String) -> io::Error {
+    io::Error::other(msg)
+}
+
+impl SocksProxy {
+    /// Opens a tunnel to `target` through the proxy, `timeout` bounds every step of the handshake.
+    pub fn connect(&self, target: SocketAddr, timeout: Duration) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect_timeout(&self.address, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        self.authenticate(&mut stream)?;
+        request_connect(&mut stream, target)?;
+        stream.set_read_timeout(None)?;
+        stream.set_write_timeout(None)?;
+        Ok(stream)
+    }
+
+    fn authenticate(&self, stream: &mut TcpStream) -> io::Result<()> {
+        let credentials = match (&self.username, &self.password) {
+            (Some(u), Some(p)) => Some((u, p)),
+            _ => None,
+        };
+        let method = if credentials.is_some() {
+            USER_PASS
+        } else {
+            NO_AUTH
+        };
+        stream.write_all(&[VERSION, 1, method])?;
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply)?;
+        if reply[0] != VERSION {
+            return Err(proxy_error(format!(
+                "Proxy {} doesn't speak SOCKS5",
+                self.address
+            )));
+        }
+        match (reply[1], credentials) {
+            (NO_AUTH, _) => Ok(()),
+            (USER_PASS, Some((username, password))) => {
+                if username.len() > 255 || password.len() > 255 {
+                    return Err(proxy_error("Proxy credentials are too long".to_string()));
+                }
+                let mut request = vec![1, username.len() as u8];
+                request.extend(username.as_bytes());
+                request.push(password.len() as u8);
+                request.extend(password.as_bytes());
+                stream.write_all(&request)?;
+                stream.read_exact(&mut reply)?;
+                if reply[1] != 0 {
+                    return Err(proxy_error(format!(
+                        "Proxy {} rejected the credentials",
+                        self.address
+                    )));
+                }
+                Ok(())
+            }
+            _ => Err(proxy_error(format!(
+                "Proxy {} doesn't accept the offered authentication",
+                self.address
+            ))),
+        }
+    }
+}
+
+fn request_connect(stream: &mut TcpStream, target: SocketAddr) -> io::Result<()> {
+    let mut request = vec![VERSION, CONNECT, 0];
+    match target.ip() {
+        IpAddr::V4(ip) => {
+            request.push(ATYP_IPV4);
+            request.extend(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            request.push(ATYP_IPV6);
+            request.extend(&ip.octets());
+        }
+    }
+    request.extend(&target.port().to_be_bytes());
+    stream.write_all(&request)?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[1] != 0 {
+        // refusals are reported like direct ones, so they're retried the same way
+        let kind = match reply[1] {
+            5 => io::ErrorKind::ConnectionRefused,
+            6 => io::ErrorKind::TimedOut,
+            _ => io::ErrorKind::Other,
+        };
+        return Err(io::Error::new(
+            kind,
+            format!(
+                "Proxy failed connecting to {}: {}",
+                target,
+                reply_message(reply[1])
+            ),
+        ));
+    }
+    // the bound address isn't needed, but has to be consumed
+    let address_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let mut len = [0; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        other => {
+            return Err(proxy_error(format!(
+                "Proxy replied with unknown address type {}",
+                other
+            )))
+        }
+    };
+    let mut bound = vec![0; address_len + 2];
+    stream.read_exact(&mut bound)?;
+    Ok(())
+}
+
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread::{spawn, JoinHandle};
+
+    /// Proxy sending `replies` right away, returning what the client sent once it hangs up.
+    fn fake_proxy(replies: Vec<u8>) -> (SocketAddr, JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let handle = spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(&replies).unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).unwrap();
+            received
+        });
+        (address, handle)
+    }
+
+    fn proxy(address: SocketAddr, credentials: Option<(&str, &str)>) -> SocksProxy {
+        SocksProxy {
+            address,
+            username: credentials.map(|(u, _)| u.to_string()),
+            password: credentials.map(|(_, p)| p.to_string()),
+        }
+    }
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn connects_without_authentication() {
+        let (address, proxy_thread) = fake_proxy(vec![5, 0, 5, 0, 0, 1, 127, 0, 0, 1, 0, 80]);
+        let target: SocketAddr = "10.0.0.1:22".parse().unwrap();
+        let stream = proxy(address, None).connect(target, TIMEOUT).unwrap();
+        drop(stream);
+        assert_eq!(
+            proxy_thread.join().unwrap(),
+            vec![5, 1, 0, 5, 1, 0, 1, 10, 0, 0, 1, 0, 22]
+        );
+    }
+
+    #[test]
+    fn authenticates_with_username_and_password() {
+        let mut replies = vec![5, 2, 1, 0, 5, 0, 0, 3, 4];
+        replies.extend(b"host\x00\x50");
+        let (address, proxy_thread) = fake_proxy(replies);
+        let target: SocketAddr = "[::1]:2222".parse().unwrap();
+        let stream = proxy(address, Some(("user", "secret")))
+            .connect(target, TIMEOUT)
+            .unwrap();
+        drop(stream);
+        let mut expected = vec![5, 1, 2, 1, 4];
+        expected.extend(b"user");
+        expected.push(6);
+        expected.extend(b"secret");
+        expected.extend(&[5, 1, 0, 4]);
+        expected.extend(&[0; 15]);
+        expected.extend(&[1, 0x08, 0xae]);
+        assert_eq!(proxy_thread.join().unwrap(), expected);
+    }
+
+    #[test]
+    fn rejected_credentials_are_an_error() {
+        let (address, proxy_thread) = fake_proxy(vec![5, 2, 1, 1]);
+        let target: SocketAddr = "10.0.0.1:22".parse().unwrap();
+        let error = proxy(address, Some(("user", "wrong")))
+            .connect(target, TIMEOUT)
+            .unwrap_err();
+        assert!(error.to_string().contains("rejected the credentials"));
+        proxy_thread.join().unwrap();
+    }
+
+    #[test]
+    fn refusals_are_reported_like_direct_ones() {
+        let (address, proxy_thread) = fake_proxy(vec![5, 0, 5, 5, 0, 1]);
+        let target: SocketAddr = "10.0.0.1:22".parse().unwrap();
+        let error = proxy(address, None).connect(target, TIMEOUT).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(error.to_string().ends_with("connection refused"));
+        proxy_thread.join().unwrap();
+    }
+
+    #[test]
+    fn unsupported_authentication_is_an_error() {
+        let (address, proxy_thread) = fake_proxy(vec![5, 0xff]);
+        let target: SocketAddr = "10.0.0.1:22".parse().unwrap();
+        assert!(proxy(address, None).connect(target, TIMEOUT).is_err());
+        proxy_thread.join().unwrap();
+    }
+
+    #[test]
+    fn proxy_passwords_are_redacted() {
+        let address = "127.0.0.1:1080".parse().unwrap();
+        let debug = format!("{:?}", proxy(address, Some(("user", "hunter2"))));
+        assert!(!debug.contains("hunter2"));
+        assert!(debug.contains("user"));
+    }
+}
@@ -0,0 +1,225 @@
+use anyhow::Error;
+use glob::Pattern;
+use std::path::{Path, PathBuf};
+
+/// Subset of `~/.ssh/config`: `Host` blocks with `HostName`, `Port`, `User`,
+/// `IdentityFile` and `ProxyJump`. `Match` blocks are ignored.
+#[derive(Debug, Default, Clone)]
+pub struct SshConfig {
+    entries: Vec<SshConfigEntry>,
+}
+
+#[derive(Debug, Clone)]
+struct SshConfigEntry {
+    /// Patterns with whether they are negated, e.g. `!bastion`.
+    patterns: Vec<(bool, Pattern)>,
+    options: Vec<(String, String)>,
+}
+
+impl SshConfigEntry {
+    fn matches(&self, host: &str) -> bool {
+        // a negated match excludes the host even if another pattern matches
+        if self
+            .patterns
+            .iter()
+            .any(|(negated, p)| *negated && p.matches(host))
+        {
+            return false;
+        }
+        self.patterns
+            .iter()
+            .any(|(negated, p)| !negated && p.matches(host))
+    }
+}
+
+/// Effective settings of a host, `None` where the config doesn't set anything.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SshHostParams {
+    pub hostname: Option<String>,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    pub identity_file: Option<PathBuf>,
+    pub proxy_jump: Option<String>,
+}
+
+impl SshConfig {
+    pub fn parse(content: &str) -> Result<SshConfig, Error> {
+        let any = Pattern::new("*")?;
+        let mut entries = vec![SshConfigEntry {
+            patterns: vec![(false, any)],
+            options: Vec::new(),
+        }];
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = match line.find(|c: char| c.is_whitespace() || c == '=') {
+                Some(i) => (
+                    &line[..i],
+                    line[i..].trim_start_matches(|c: char| c.is_whitespace() || c == '='),
+                ),
+                None => (line, ""),
+            };
+            let value = value.trim().trim_matches('"');
+            match key.to_lowercase().as_str() {
+                "host" => {
+                    let patterns = value
+                        .split_whitespace()
+                        .map(|p| {
+                            let (negated, p) = match p.strip_prefix('!') {
+                                Some(p) => (true, p),
+                                None => (false, p),
+                            };
+                            Pattern::new(p).map(|p| (negated, p)).map_err(|e| {
+                                Error::msg(format!(
+                                    "Invalid Host pattern on line {}: {}",
+                                    number + 1,
+                                    e
+                                ))
+                            })
+                        })
+                        .collect::<Result<_, _>>()?;
+                    entries.push(SshConfigEntry {
+                        patterns,
+                        options: Vec::new(),
+                    });
+                }
+                // unsupported, the block's options are skipped until the next Host
+                "match" => entries.push(SshConfigEntry {
+                    patterns: Vec::new(),
+                    options: Vec::new(),
+                }),
+                key => {
+                    if let Some(entry) = entries.last_mut() {
+                        entry.options.push((key.to_string(), value.to_string()));
+                    }
+                }
+            }
+        }
+        Ok(SshConfig { entries })
+    }
+
+    /// Loads `~/.ssh/config`, a missing file is an empty config.
+    pub fn load_default() -> Result<SshConfig, Error> {
+        let path = match std::env::var_os("HOME") {
+            Some(home) => Path::new(&home).join(".ssh/config"),
+            None => return Ok(SshConfig::default()),
+        };
+        if !path.exists() {
+            return Ok(SshConfig::default());
+        }
+        let content = std::fs::read_to_string(&path)
+            .map_err(|e| Error::msg(format!("Failed reading {}: {}", path.display(), e)))?;
+        SshConfig::parse(&content)
+    }
+
+    /// Like ssh, the first value found for an option wins.
+    pub fn host_params(&self, host: &str) -> SshHostParams {
+        let mut params = SshHostParams::default();
+        for entry in self.entries.iter().filter(|e| e.matches(host)) {
+            for (key, value) in &entry.options {
+                match key.as_str() {
+                    "hostname" if params.hostname.is_none() => {
+                        params.hostname = Some(value.replace("%h", host))
+                    }
+                    "port" if params.port.is_none() => params.port = value.parse().ok(),
+                    "user" if params.user.is_none() => params.user = Some(value.clone()),
+                    "identityfile" if params.identity_file.is_none() => {
+                        params.identity_file = Some(expand_tilde(value))
+                    }
+                    "proxyjump" if params.proxy_jump.is_none() => {
+                        params.proxy_jump = Some(value.clone())
+                    }
+                    _ => {}
+                }
+            }
+        }
+        // `ProxyJump none` disables a jump host set by a broader block
+        if params.proxy_jump.as_deref() == Some("none") {
+            params.proxy_jump = None;
+        }
+        params
+    }
+}
+
+fn expand_tilde(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => Path::new(&home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "\
+        # defaults come last, the first value wins\n\
+        Host web-direct\n\
+        \x20   ProxyJump none\n\
+        \n\
+        Host web*\n\
+        \x20   HostName %h.internal.example.com\n\
+        \x20   Port 2222\n\
+        \x20   ProxyJump bastion\n\
+        \n\
+        Host db !db-legacy\n\
+        \x20   User=dba\n\
+        \x20   IdentityFile \"/keys/db key\"\n\
+        \n\
+        Match exec \"true\"\n\
+        \x20   User matched\n\
+        \n\
+        Host *\n\
+        \x20   User deploy\n\
+        \x20   Port 22\n";
+
+    #[test]
+    fn first_matching_value_wins() {
+        let config = SshConfig::parse(CONFIG).unwrap();
+        assert_eq!(
+            config.host_params("web1"),
+            SshHostParams {
+                hostname: Some("web1.internal.example.com".to_string()),
+                port: Some(2222),
+                user: Some("deploy".to_string()),
+                identity_file: None,
+                proxy_jump: Some("bastion".to_string()),
+            }
+        );
+        assert_eq!(config.host_params("other").port, Some(22));
+    }
+
+    #[test]
+    fn equals_signs_and_quotes_are_accepted() {
+        let params = SshConfig::parse(CONFIG).unwrap().host_params("db");
+        assert_eq!(params.user.as_deref(), Some("dba"));
+        assert_eq!(params.identity_file, Some(PathBuf::from("/keys/db key")));
+    }
+
+    #[test]
+    fn negated_patterns_exclude_hosts() {
+        let params = SshConfig::parse(CONFIG).unwrap().host_params("db-legacy");
+        assert_eq!(params.user.as_deref(), Some("deploy"));
+        assert_eq!(params.identity_file, None);
+    }
+
+    #[test]
+    fn match_blocks_are_skipped() {
+        let params = SshConfig::parse(CONFIG).unwrap().host_params("anything");
+        assert_eq!(params.user.as_deref(), Some("deploy"));
+    }
+
+    #[test]
+    fn proxy_jump_none_disables_the_jump_host() {
+        let config = SshConfig::parse(CONFIG).unwrap();
+        assert_eq!(config.host_params("web-direct").proxy_jump, None);
+        assert_eq!(config.host_params("web-direct").port, Some(2222));
+    }
+
+    #[test]
+    fn invalid_host_patterns_are_rejected() {
+        assert!(SshConfig::parse("Host web[\n").is_err());
+    }
+}